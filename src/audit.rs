@@ -0,0 +1,260 @@
+use comfy_table::{modifiers::UTF8_ROUND_CORNERS, presets::UTF8_FULL, ContentArrangement, Table};
+use eyre::Result;
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::Serialize;
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+use walkdir::WalkDir;
+
+use crate::{
+    error::EXIT_PARTIAL, formats::Format, is_journal_dir, journal_dir, metadata::BookMetadata, Args,
+};
+
+/// Extensions which give away a title that was copied from a file name
+const FILE_EXTENSIONS: [&str; 14] = [
+    ".doc", ".docx", ".rtf", ".odt", ".txt", ".pdf", ".epub", ".mobi", ".azw3", ".html", ".htm",
+    ".indd", ".pages", ".tex",
+];
+/// Prefixes word processors and converters put in front of the document's file name
+const FILENAME_PREFIXES: [&str; 3] = ["microsoft word - ", "microsoft powerpoint - ", "untitled"];
+/// Creators which are placeholders or the name of a tool rather than a person
+const JUNK_CREATORS: [&str; 10] = [
+    "unknown",
+    "unknown author",
+    "calibre",
+    "admin",
+    "administrator",
+    "user",
+    "author",
+    "owner",
+    "none",
+    "n/a",
+];
+
+/// A problem with a book's metadata
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Issue {
+    /// The book could not be read at all
+    Unreadable,
+    MissingTitle,
+    MissingCreator,
+    MissingLanguage,
+    MissingIdentifier,
+    /// The title looks like a file name, e.g. `Microsoft Word - draft.doc`
+    FilenameTitle,
+    /// A creator is a placeholder such as `Unknown`, or a tool such as `calibre`
+    JunkCreator,
+    /// An ISBN with the wrong length or checksum
+    InvalidIsbn,
+}
+
+impl Issue {
+    fn label(&self) -> &'static str {
+        match self {
+            Issue::Unreadable => "Unreadable",
+            Issue::MissingTitle => "Missing title",
+            Issue::MissingCreator => "Missing creator",
+            Issue::MissingLanguage => "Missing language",
+            Issue::MissingIdentifier => "Missing identifier",
+            Issue::FilenameTitle => "Title looks like a file name",
+            Issue::JunkCreator => "Placeholder creator",
+            Issue::InvalidIsbn => "Invalid ISBN",
+        }
+    }
+}
+
+/// A single problem found in a single book
+#[derive(Debug, Serialize)]
+pub struct Finding {
+    pub path: PathBuf,
+    pub issue: Issue,
+    /// The offending value, or the read error
+    pub detail: String,
+}
+
+/// Read every EPUB under root and report the books with missing or junk metadata
+pub fn audit(args: &Args, root: &Path, output: &Path, csv: Option<&Path>) -> Result<ExitCode> {
+    if !root.is_dir() {
+        return Err(eyre::eyre!("{} is not a directory", root.display()));
+    }
+
+    // Metadata backups look like any other EPUB, but are not part of the library
+    let journal_dir = journal_dir(output);
+    let books = WalkDir::new(root)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| !is_journal_dir(entry.path(), journal_dir.as_deref()))
+        .filter_map(Result::ok)
+        .filter(|entry| Format::from_path(entry.path()) == Some(Format::Epub))
+        .map(|entry| entry.into_path())
+        .collect::<Vec<_>>();
+
+    let bar = ProgressBar::new(books.len() as u64).with_style(
+        ProgressStyle::default_bar().template("Auditing {wide_bar:.cyan/blue} {pos}/{len}")?,
+    );
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.jobs.unwrap_or_default())
+        .build()?;
+    let mut findings = pool.install(|| {
+        books
+            .par_iter()
+            .flat_map_iter(|path| {
+                let findings = match Format::Epub.reader().read(path) {
+                    Ok(book) => check(path, &book),
+                    Err(e) => vec![Finding {
+                        path: path.clone(),
+                        issue: Issue::Unreadable,
+                        detail: e.to_string(),
+                    }],
+                };
+                bar.inc(1);
+                findings
+            })
+            .collect::<Vec<_>>()
+    });
+    bar.finish_and_clear();
+    findings.sort_by(|a, b| (a.issue, &a.path).cmp(&(b.issue, &b.path)));
+
+    for issue in findings.iter().map(|f| f.issue).collect::<BTreeSet<_>>() {
+        let group = findings.iter().filter(|f| f.issue == issue);
+        let mut table = Table::new();
+        table
+            .load_preset(UTF8_FULL)
+            .apply_modifier(UTF8_ROUND_CORNERS)
+            .set_content_arrangement(ContentArrangement::Dynamic)
+            .set_header(vec![issue.label(), "Detail"]);
+        for finding in group {
+            table.add_row(vec![
+                finding.path.to_string_lossy().to_string(),
+                finding.detail.clone(),
+            ]);
+        }
+        println!("{table}");
+    }
+
+    let affected = findings.iter().map(|f| &f.path).collect::<BTreeSet<_>>();
+    println!(
+        "Audited {} books, {} with issues",
+        books.len(),
+        affected.len()
+    );
+
+    if let Some(path) = csv {
+        let mut writer = csv::Writer::from_path(path)
+            .map_err(|e| eyre::eyre!("Failed to write {}: {e}", path.display()))?;
+        for finding in &findings {
+            writer.serialize(finding)?;
+        }
+        writer.flush()?;
+        println!("Findings written to {}", path.display());
+    }
+
+    match findings.iter().any(|f| f.issue == Issue::Unreadable) {
+        true => Ok(ExitCode::from(EXIT_PARTIAL)),
+        false => Ok(ExitCode::SUCCESS),
+    }
+}
+
+/// Every problem with a single book's metadata
+fn check(path: &Path, book: &BookMetadata) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut found = |issue, detail: &str| {
+        findings.push(Finding {
+            path: path.to_path_buf(),
+            issue,
+            detail: detail.to_string(),
+        })
+    };
+
+    match book.title() {
+        None => found(Issue::MissingTitle, ""),
+        Some(title) if looks_like_filename(title) => found(Issue::FilenameTitle, title),
+        Some(_) => {}
+    }
+    if book.authors.is_empty() {
+        found(Issue::MissingCreator, "");
+    }
+    // Only `dc:creator`, since calibre credits itself as a `dc:contributor` of every book it
+    // converts
+    for creator in &book.authors {
+        if is_junk_creator(&creator.name) {
+            found(Issue::JunkCreator, &creator.name);
+        }
+    }
+    if book.languages.is_empty() {
+        found(Issue::MissingLanguage, "");
+    }
+    if book.identifiers.is_empty() {
+        found(Issue::MissingIdentifier, "");
+    }
+    for isbn in book.identifiers.iter().filter(|id| id.is_isbn()) {
+        if !is_valid_isbn(&isbn.value) {
+            found(Issue::InvalidIsbn, &isbn.value);
+        }
+    }
+
+    findings
+}
+
+fn looks_like_filename(title: &str) -> bool {
+    let lower = title.trim().to_lowercase();
+    FILENAME_PREFIXES
+        .iter()
+        .any(|prefix| lower.starts_with(prefix))
+        || FILE_EXTENSIONS.iter().any(|ext| lower.ends_with(ext))
+        // Words joined with underscores, e.g. `my_book_final_v2`
+        || (lower.contains('_') && !lower.contains(' '))
+}
+
+fn is_junk_creator(name: &str) -> bool {
+    let lower = name.trim().to_lowercase();
+    // calibre credits itself with its version, e.g. `calibre (6.11.0)`
+    JUNK_CREATORS.contains(&lower.as_str()) || lower.starts_with("calibre (")
+}
+
+/// Whether an ISBN-10 or ISBN-13 has the right length and checksum, ignoring hyphens and spaces
+fn is_valid_isbn(isbn: &str) -> bool {
+    let chars = isbn
+        .chars()
+        .filter(|c| !matches!(c, '-' | ' '))
+        .map(|c| c.to_ascii_uppercase())
+        .collect::<Vec<_>>();
+
+    match chars.len() {
+        10 => {
+            let mut sum = 0;
+            for (i, c) in chars.iter().enumerate() {
+                let digit = match c {
+                    'X' if i == 9 => 10,
+                    c => match c.to_digit(10) {
+                        Some(digit) => digit,
+                        None => return false,
+                    },
+                };
+                sum += digit * (10 - i as u32);
+            }
+            sum % 11 == 0
+        }
+        13 => {
+            let Some(digits) = chars
+                .iter()
+                .map(|c| c.to_digit(10))
+                .collect::<Option<Vec<_>>>()
+            else {
+                return false;
+            };
+            let sum = digits
+                .iter()
+                .enumerate()
+                .map(|(i, digit)| if i % 2 == 0 { *digit } else { digit * 3 })
+                .sum::<u32>();
+            sum % 10 == 0
+        }
+        _ => false,
+    }
+}
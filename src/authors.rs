@@ -0,0 +1,447 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, path::Path};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+use crate::metadata::{BookMetadata, Contributor};
+
+/// Which form of an author's name to use when building paths
+#[derive(ValueEnum, Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum NameForm {
+    /// The name as it would be printed on a cover, e.g. `J. R. R. Tolkien`
+    #[default]
+    Display,
+    /// The name in sort order, e.g. `Tolkien, J. R. R.`
+    Sort,
+}
+
+/// How books credited to more than one author are placed
+#[derive(ValueEnum, Serialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MultiAuthor {
+    /// Only the first author
+    First,
+    /// Every author, up to the maximum, followed by `et al.`
+    #[default]
+    All,
+    /// Only the authors credited with the `aut` role, leaving out editors, illustrators and
+    /// the like, up to the maximum
+    PrimaryRoleOnly,
+    /// Books with more authors than the maximum, or only editors, are placed in an
+    /// `Anthologies` folder
+    Anthology,
+}
+
+impl fmt::Display for MultiAuthor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.to_possible_value() {
+            Some(value) => write!(f, "{}", value.get_name()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The folder name used for anthologies by [`MultiAuthor::Anthology`]
+const ANTHOLOGY: &str = "Anthologies";
+
+/// Roles which compile a book rather than write it
+const COMPILER_ROLES: &[&str] = &["edt", "com"];
+
+/// How the authors of a book are credited in its path
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AuthorPolicy {
+    pub form: NameForm,
+    pub multi_author: MultiAuthor,
+    /// The number of authors credited before the rest are replaced with `et al.`
+    pub max_authors: usize,
+}
+
+impl AuthorPolicy {
+    /// The author credit for a book, e.g. `Neil Gaiman, Terry Pratchett`, in the given form
+    pub fn credit(&self, authors: &[Contributor], form: NameForm) -> Option<String> {
+        let max = self.max_authors.max(1);
+        let credited = match self.multi_author {
+            MultiAuthor::First => authors.iter().take(1).collect::<Vec<_>>(),
+            MultiAuthor::All => authors.iter().collect(),
+            MultiAuthor::PrimaryRoleOnly => {
+                // Creators without a role are assumed to be authors, and if every creator has
+                // another role the first one is credited rather than none
+                let primary = authors
+                    .iter()
+                    .filter(|a| a.roles.is_empty() || a.has_role("aut"))
+                    .collect::<Vec<_>>();
+                if primary.is_empty() {
+                    authors.iter().take(1).collect()
+                } else {
+                    primary
+                }
+            }
+            MultiAuthor::Anthology => {
+                let only_compilers = !authors.is_empty()
+                    && authors
+                        .iter()
+                        .all(|a| COMPILER_ROLES.iter().any(|role| a.has_role(role)));
+                if authors.len() > max || only_compilers {
+                    return Some(ANTHOLOGY.to_string());
+                }
+                authors.iter().collect()
+            }
+        };
+        if credited.is_empty() {
+            return None;
+        }
+
+        let names = credited
+            .iter()
+            .take(max)
+            .map(|a| a.name_in(form))
+            .collect::<Vec<_>>()
+            .join(separator(form));
+        if credited.len() > max {
+            Some(format!("{names} et al."))
+        } else {
+            Some(names)
+        }
+    }
+}
+
+/// The separator between names, which is an ampersand in sort form as the names contain commas
+pub fn separator(form: NameForm) -> &'static str {
+    match form {
+        NameForm::Display => ", ",
+        NameForm::Sort => " & ",
+    }
+}
+
+/// Name suffixes which stay after the given names when a name is inverted
+const SUFFIXES: &[&str] = &["jr", "jr.", "sr", "sr.", "ii", "iii", "iv", "phd", "md"];
+
+/// Surname particles, which stay lower case when fixing the case of a name
+const PARTICLES: &[&str] = &[
+    "van", "von", "de", "der", "den", "da", "di", "du", "la", "le",
+];
+
+/// An alias file, mapping each canonical author name to the variants which should be replaced
+/// with it:
+///
+/// ```toml
+/// [aliases]
+/// "J. R. R. Tolkien" = ["JRR Tolkien", "John Ronald Reuel Tolkien"]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+struct AliasFile {
+    #[serde(default)]
+    aliases: HashMap<String, Vec<String>>,
+}
+
+/// Normalizes author names so that variants of the same name end up in the same folder
+#[derive(Debug, Default)]
+pub struct AuthorNormalizer {
+    /// Canonical display names, keyed by the comparison key of each known variant
+    aliases: HashMap<String, String>,
+}
+
+impl AuthorNormalizer {
+    /// Load a TOML alias file
+    pub fn from_alias_file(path: &Path) -> eyre::Result<AuthorNormalizer> {
+        let contents = std::fs::read_to_string(path)?;
+        let file: AliasFile = toml::from_str(&contents)
+            .map_err(|e| eyre::eyre!("Invalid alias file {}: {e}", path.display()))?;
+
+        let mut aliases = HashMap::new();
+        for (canonical, variants) in file.aliases {
+            let canonical = display_name(&canonical);
+            for variant in variants.iter().chain(std::iter::once(&canonical)) {
+                aliases.insert(comparison_key(&display_name(variant)), canonical.clone());
+            }
+        }
+
+        Ok(AuthorNormalizer { aliases })
+    }
+
+    /// Normalize every author and contributor of a book in place, setting `name` to the display
+    /// form and `file_as` to the sort form. A creator which lists several people is split into
+    /// one contributor for each of them.
+    pub fn normalize(&self, metadata: &mut BookMetadata) {
+        for contributors in [&mut metadata.authors, &mut metadata.contributors] {
+            *contributors = std::mem::take(contributors)
+                .into_iter()
+                .flat_map(split_contributor)
+                .collect();
+            for contributor in contributors.iter_mut() {
+                self.normalize_contributor(contributor);
+            }
+        }
+    }
+
+    fn normalize_contributor(&self, contributor: &mut Contributor) {
+        let display = display_name(&contributor.name);
+
+        match self.aliases.get(&comparison_key(&display)) {
+            Some(canonical) => {
+                contributor.file_as = Some(sort_name(canonical));
+                contributor.name = canonical.clone();
+            }
+            None => {
+                // A sort name from the book is preferred, as inverting a name is only a guess
+                contributor.file_as = Some(
+                    contributor
+                        .file_as
+                        .as_deref()
+                        .map(normalize_spelling)
+                        .filter(|file_as| file_as.contains(','))
+                        .unwrap_or_else(|| sort_name(&display)),
+                );
+                contributor.name = display;
+            }
+        }
+    }
+}
+
+/// The people credited in a single contributor, sharing its roles. A sort name only applies to
+/// one person, so it is dropped when there are several.
+fn split_contributor(contributor: Contributor) -> Vec<Contributor> {
+    let names = display_names(&contributor.name);
+    if names.len() < 2 {
+        return vec![contributor];
+    }
+    names
+        .into_iter()
+        .map(|name| Contributor {
+            roles: contributor.roles.clone(),
+            ..Contributor::new(name)
+        })
+        .collect()
+}
+
+impl Contributor {
+    /// The contributor's name in the requested form
+    pub fn name_in(&self, form: NameForm) -> &str {
+        match form {
+            NameForm::Display => &self.name,
+            NameForm::Sort => self.file_as.as_deref().unwrap_or(&self.name),
+        }
+    }
+}
+
+/// Collapse whitespace, space out initials (`J.R.R.` becomes `J. R. R.`) and fix the case of
+/// names written entirely in upper or lower case
+fn normalize_spelling(name: &str) -> String {
+    let spaced = name
+        .replace('.', ". ")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace(" ,", ",");
+
+    let mut letters = spaced.chars().filter(|c| c.is_alphabetic());
+    let uniform_case = letters.clone().all(char::is_lowercase) || letters.all(char::is_uppercase);
+
+    spaced
+        .split(' ')
+        .enumerate()
+        .map(|(i, word)| {
+            if uniform_case && i > 0 && is_particle(word) {
+                word.to_lowercase()
+            } else if is_initial(word) || uniform_case {
+                capitalize(word)
+            } else {
+                word.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first
+            .to_uppercase()
+            .chain(chars.flat_map(char::to_lowercase))
+            .collect(),
+        None => String::new(),
+    }
+}
+
+/// The display form of a name, turning a sort form such as `Tolkien, J.R.R.` back around. A
+/// list of names such as `Neil Gaiman, Terry Pratchett` is left as it is.
+pub fn display_name(name: &str) -> String {
+    let name = normalize_spelling(name);
+    invert(&name).unwrap_or(name)
+}
+
+/// The display form of every name in a creator, which sometimes lists several people, as in
+/// `Neil Gaiman, Terry Pratchett`
+fn display_names(name: &str) -> Vec<String> {
+    let name = normalize_spelling(name);
+    if let Some(display) = invert(&name) {
+        return vec![display];
+    }
+    let names = name.split(", ").collect::<Vec<_>>();
+    if names.iter().any(|name| name.is_empty() || is_suffix(name)) {
+        return vec![name];
+    }
+    names.into_iter().map(str::to_string).collect()
+}
+
+/// The display form of a normalized name in sort form, or `None` if it isn't one
+fn invert(name: &str) -> Option<String> {
+    match name.split(", ").collect::<Vec<_>>().as_slice() {
+        [last, first] if !is_suffix(first) && is_sort_form(last, first) => {
+            Some(format!("{first} {last}"))
+        }
+        [last, first, suffix] if is_suffix(suffix) && is_sort_form(last, first) => {
+            Some(format!("{first} {last} {suffix}"))
+        }
+        _ => None,
+    }
+}
+
+/// Whether `last, first` reads as one name in sort form rather than as two names. It is taken
+/// as two names only when `last` is more than a surname with its particles and `first` has
+/// several words but no initials, as in `Neil Gaiman, Terry Pratchett`.
+fn is_sort_form(last: &str, first: &str) -> bool {
+    let surname = match last.rsplit_once(' ') {
+        Some((particles, _)) => particles.split(' ').all(is_particle),
+        None => true,
+    };
+    let given_names = !first.contains(' ') || first.split(' ').any(is_initial);
+    surname || given_names
+}
+
+/// The sort form of a display name, e.g. `Ludwig van Beethoven` becomes `Beethoven, Ludwig van`.
+/// Particles stay with the given names, as calibre does.
+pub fn sort_name(display: &str) -> String {
+    let mut words = display.split(' ').collect::<Vec<_>>();
+
+    let suffix = match words.last() {
+        Some(last) if words.len() > 2 && is_suffix(last) => words.pop(),
+        _ => None,
+    };
+    if words.len() < 2 {
+        return display.to_string();
+    }
+
+    let last = words.pop().expect("there are at least two words");
+    let given = words.join(" ");
+    match suffix {
+        Some(suffix) => format!("{last}, {given}, {suffix}"),
+        None => format!("{last}, {given}"),
+    }
+}
+
+fn is_suffix(word: &str) -> bool {
+    SUFFIXES.contains(&word.to_lowercase().as_str())
+}
+
+fn is_particle(word: &str) -> bool {
+    PARTICLES.contains(&word.to_lowercase().as_str())
+}
+
+fn is_initial(word: &str) -> bool {
+    word.trim_end_matches(['.', ',']).chars().count() == 1
+}
+
+/// A key which is equal for names that only differ in punctuation, spacing, case or accents
+pub fn comparison_key(name: &str) -> String {
+    name.nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spaces_initials_and_fixes_case() {
+        assert_eq!(normalize_spelling("J.R.R. Tolkien"), "J. R. R. Tolkien");
+        assert_eq!(normalize_spelling("TOLKIEN, J.R.R."), "Tolkien, J. R. R.");
+        assert_eq!(
+            normalize_spelling("ludwig van beethoven"),
+            "Ludwig van Beethoven"
+        );
+        assert_eq!(
+            normalize_spelling("Le Guin , Ursula K."),
+            "Le Guin, Ursula K."
+        );
+    }
+
+    #[test]
+    fn inverts_sort_forms() {
+        assert_eq!(display_name("J.R.R. Tolkien"), "J. R. R. Tolkien");
+        assert_eq!(display_name("Tolkien, J.R.R."), "J. R. R. Tolkien");
+        assert_eq!(display_name("Le Guin, Ursula K."), "Ursula K. Le Guin");
+        assert_eq!(display_name("Smith, John, Jr."), "John Smith Jr.");
+        assert_eq!(display_name("Smith, Jr."), "Smith, Jr.");
+        assert_eq!(
+            display_name("García Márquez, Gabriel"),
+            "Gabriel García Márquez"
+        );
+    }
+
+    #[test]
+    fn leaves_lists_of_names_alone() {
+        assert_eq!(
+            display_name("Neil Gaiman, Terry Pratchett"),
+            "Neil Gaiman, Terry Pratchett"
+        );
+        assert_eq!(
+            display_names("Neil Gaiman, Terry Pratchett"),
+            ["Neil Gaiman", "Terry Pratchett"]
+        );
+        assert_eq!(display_names("Tolkien, J.R.R."), ["J. R. R. Tolkien"]);
+        assert_eq!(display_names("Smith, John, Jr."), ["John Smith Jr."]);
+    }
+
+    #[test]
+    fn builds_sort_forms() {
+        assert_eq!(sort_name("J. R. R. Tolkien"), "Tolkien, J. R. R.");
+        assert_eq!(sort_name("John Smith Jr."), "Smith, John, Jr.");
+        assert_eq!(sort_name("Ludwig van Beethoven"), "Beethoven, Ludwig van");
+        assert_eq!(sort_name("Homer"), "Homer");
+    }
+
+    #[test]
+    fn splits_creators_listing_several_people() {
+        let mut metadata = BookMetadata {
+            authors: vec![Contributor {
+                name: "Neil Gaiman, Terry Pratchett".to_string(),
+                file_as: Some("Gaiman, Neil & Pratchett, Terry".to_string()),
+                roles: vec!["aut".to_string()],
+            }],
+            ..Default::default()
+        };
+        AuthorNormalizer::default().normalize(&mut metadata);
+
+        let names = metadata
+            .authors
+            .iter()
+            .map(|a| {
+                (
+                    a.name.as_str(),
+                    a.name_in(NameForm::Sort),
+                    a.roles.as_slice(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                (
+                    "Neil Gaiman",
+                    "Gaiman, Neil",
+                    ["aut".to_string()].as_slice()
+                ),
+                (
+                    "Terry Pratchett",
+                    "Pratchett, Terry",
+                    ["aut".to_string()].as_slice()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use crate::Args;
+
+/// Settings which can be stored in the config file. Every key is named after the command line
+/// flag it provides a value for, e.g. `on-conflict` for `--on-conflict`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Settings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    root: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strategy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    on_conflict: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    author_form: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    multi_author: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_authors: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aliases: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sanitize: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_name_bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    formats: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filename_pattern: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duplicates: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duplicates_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jobs: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    report: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    report_format: Option<String>,
+}
+
+impl Settings {
+    /// The effective settings of a run, after merging the config file and command line
+    pub fn from_args(args: &Args, root: &Path, output: &Path) -> Settings {
+        Settings {
+            root: Some(root.to_path_buf()),
+            output: Some(output.to_path_buf()),
+            strategy: Some(name(&args.strategy)),
+            on_conflict: Some(name(&args.on_conflict)),
+            template: Some(args.template.to_string()),
+            author_form: Some(name(&args.author_form)),
+            multi_author: Some(name(&args.multi_author)),
+            max_authors: Some(args.max_authors),
+            aliases: args.aliases.clone(),
+            sanitize: Some(name(&args.sanitize)),
+            max_name_bytes: Some(args.max_name_bytes),
+            formats: Some(args.formats.iter().map(name).collect()),
+            filename_pattern: (!args.filename_pattern.is_empty()).then(|| {
+                args.filename_pattern
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect()
+            }),
+            duplicates: args.duplicates.as_ref().map(name),
+            duplicates_dir: args.duplicates_dir.clone(),
+            jobs: args.jobs,
+            report: args.report.clone(),
+            report_format: Some(name(&args.report_format)),
+        }
+    }
+
+    /// Layer `other` on top of these settings
+    fn merge(self, other: Settings) -> Settings {
+        Settings {
+            root: other.root.or(self.root),
+            output: other.output.or(self.output),
+            strategy: other.strategy.or(self.strategy),
+            on_conflict: other.on_conflict.or(self.on_conflict),
+            template: other.template.or(self.template),
+            author_form: other.author_form.or(self.author_form),
+            multi_author: other.multi_author.or(self.multi_author),
+            max_authors: other.max_authors.or(self.max_authors),
+            aliases: other.aliases.or(self.aliases),
+            sanitize: other.sanitize.or(self.sanitize),
+            max_name_bytes: other.max_name_bytes.or(self.max_name_bytes),
+            formats: other.formats.or(self.formats),
+            filename_pattern: other.filename_pattern.or(self.filename_pattern),
+            duplicates: other.duplicates.or(self.duplicates),
+            duplicates_dir: other.duplicates_dir.or(self.duplicates_dir),
+            jobs: other.jobs.or(self.jobs),
+            report: other.report.or(self.report),
+            report_format: other.report_format.or(self.report_format),
+        }
+    }
+
+    /// Resolve `~` and paths relative to the config file's directory
+    fn resolve_paths(mut self, base: &Path) -> Settings {
+        for path in [
+            &mut self.root,
+            &mut self.output,
+            &mut self.aliases,
+            &mut self.duplicates_dir,
+            &mut self.report,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = match path.strip_prefix("~") {
+                Ok(rest) => home_dir().unwrap_or_default().join(rest),
+                Err(_) => base.join(&path),
+            };
+        }
+        self
+    }
+
+    /// The command line flags equivalent to these settings, leaving out the flags for which
+    /// `is_set` returns true
+    fn to_flags(&self, is_set: impl Fn(&str) -> bool) -> Result<Vec<OsString>> {
+        let toml::Value::Table(table) = toml::Value::try_from(self)? else {
+            return Ok(Vec::new());
+        };
+
+        let mut flags = Vec::new();
+        for (key, value) in table {
+            if is_set(&key.replace('-', "_")) {
+                continue;
+            }
+            // Lists repeat the flag once per value, since values such as regular expressions may
+            // contain the delimiter. Values are attached with `=` so that those starting with `-`
+            // aren't taken for flags.
+            let values = match value {
+                toml::Value::String(value) => vec![value],
+                toml::Value::Array(values) => values
+                    .iter()
+                    .filter_map(|value| value.as_str())
+                    .map(str::to_string)
+                    .collect(),
+                value => vec![value.to_string()],
+            };
+            for value in values {
+                flags.push(format!("--{key}={value}").into());
+            }
+        }
+        Ok(flags)
+    }
+}
+
+/// A config file, with default settings and any number of named profiles, e.g.
+///
+/// ```toml
+/// [defaults]
+/// on-conflict = "rename"
+///
+/// [profiles.nas]
+/// root = "/mnt/books/inbox"
+/// output = "/mnt/books/library"
+/// strategy = "copy"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    defaults: Settings,
+    #[serde(default)]
+    profiles: BTreeMap<String, Settings>,
+}
+
+/// The config file and profile a run's settings were loaded from
+pub struct Source {
+    pub path: PathBuf,
+    pub profile: Option<String>,
+}
+
+/// Parse the command line, filling in every setting it leaves out from the config file
+pub fn parse_args() -> Result<(Args, Option<Source>)> {
+    let argv = std::env::args_os().collect::<Vec<_>>();
+    let matches = Args::command().get_matches_from(&argv);
+    let args = Args::from_arg_matches(&matches)?;
+
+    let path = match args.config {
+        Some(ref path) => path.clone(),
+        None => match default_path().filter(|path| path.exists()) {
+            Some(path) => path,
+            None if args.profile.is_some() => {
+                return Err(eyre!(
+                    "--profile requires a config file, but none was found"
+                ))
+            }
+            None => return Ok((args, None)),
+        },
+    };
+
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|e| eyre!("Failed to read config file {}: {e}", path.display()))?;
+    let config: Config = toml::from_str(&contents)
+        .map_err(|e| eyre!("Invalid config file {}: {e}", path.display()))?;
+
+    let mut settings = config.defaults;
+    if let Some(ref profile) = args.profile {
+        let Some(overrides) = config.profiles.get(profile) else {
+            let known = config.profiles.keys().cloned().collect::<Vec<_>>();
+            return Err(eyre!(
+                "No profile named {profile} in {}, expected one of: {}",
+                path.display(),
+                known.join(", ")
+            ));
+        };
+        settings = settings.merge(overrides.clone());
+    }
+    let base = path.parent().unwrap_or(Path::new("."));
+    let settings = settings.resolve_paths(base);
+
+    // The config's settings are passed as flags ahead of the user's own, so that clap validates
+    // them the same way, and flags given on the command line are left out so that they win
+    let flags = settings.to_flags(|id| from_command_line(&matches, id))?;
+    let argv = argv
+        .iter()
+        .take(1)
+        .cloned()
+        .chain(flags)
+        .chain(argv.iter().skip(1).cloned());
+    let args = Args::try_parse_from(argv)
+        .map_err(|e| eyre!("Invalid setting in config file {}: {e}", path.display()))?;
+    let source = Source {
+        path,
+        profile: args.profile.clone(),
+    };
+
+    Ok((args, Some(source)))
+}
+
+/// `~/.config/ebook-sorter/config.toml`, or the same under `$XDG_CONFIG_HOME`
+fn default_path() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| home_dir().map(|home| home.join(".config")))?;
+    Some(config_dir.join("ebook-sorter").join("config.toml"))
+}
+
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(PathBuf::from)
+}
+
+fn from_command_line(matches: &ArgMatches, id: &str) -> bool {
+    matches.try_contains_id(id).unwrap_or_default()
+        && matches.value_source(id) == Some(ValueSource::CommandLine)
+}
+
+/// The name a value is given on the command line
+fn name<T: ValueEnum>(value: &T) -> String {
+    value
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default()
+}
@@ -0,0 +1,378 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::{authors::comparison_key, metadata::BookMetadata, place};
+
+/// What to do with the extra copies in a group of duplicates
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// List the duplicates but place every copy as usual
+    Report,
+    /// Place the first copy, in path order, and leave the others where they are
+    KeepFirst,
+    /// Place the largest copy and leave the others where they are
+    KeepLargest,
+    /// Place the first copy and move the others into the `--duplicates-dir` directory
+    MoveTo,
+}
+
+/// Why a set of files was grouped as duplicates
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "key", rename_all = "snake_case")]
+pub enum DuplicateKind {
+    /// The files have identical contents, identified by their BLAKE3 hash
+    Exact(String),
+    /// The books share an ISBN, given as ISBN-13
+    Isbn(String),
+    /// The books have the same title and authors, ignoring case, punctuation and accents
+    TitleAuthor(String),
+}
+
+impl fmt::Display for DuplicateKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DuplicateKind::Exact(_) => write!(f, "identical contents"),
+            DuplicateKind::Isbn(isbn) => write!(f, "same ISBN {isbn}"),
+            DuplicateKind::TitleAuthor(_) => write!(f, "same title and author"),
+        }
+    }
+}
+
+/// What happens to a single copy in a group of duplicates
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", content = "destination", rename_all = "snake_case")]
+pub enum DuplicateAction {
+    /// The copy is placed as usual
+    Place,
+    /// The copy is left where it is
+    Skip,
+    /// The copy is moved into the duplicates directory instead of being placed
+    MoveTo(PathBuf),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateMember {
+    pub path: PathBuf,
+    pub size: u64,
+    #[serde(flatten)]
+    pub action: DuplicateAction,
+}
+
+/// A set of ebooks which are copies of the same book
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateGroup {
+    #[serde(flatten)]
+    pub kind: DuplicateKind,
+    /// The copy which is considered the original
+    pub keep: PathBuf,
+    pub members: Vec<DuplicateMember>,
+}
+
+impl DuplicateGroup {
+    /// The members other than the original
+    fn copies(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        self.members
+            .iter()
+            .filter(|member| member.path != self.keep)
+            .map(|member| member.path.clone())
+    }
+
+    /// Every member on its own line, marking the original and what happens to the other copies
+    pub fn members_cell(&self) -> String {
+        let resolved = self
+            .members
+            .iter()
+            .any(|member| member.action != DuplicateAction::Place);
+        self.members
+            .iter()
+            .map(|member| {
+                let path = member.path.to_string_lossy();
+                match &member.action {
+                    _ if resolved && member.path == self.keep => format!("{path} (kept)"),
+                    DuplicateAction::Place => path.to_string(),
+                    DuplicateAction::Skip => format!("{path} (skipped)"),
+                    DuplicateAction::MoveTo(_) => format!("{path} (moved)"),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// What the duplicate pass knows about a single discovered ebook
+struct Candidate {
+    path: PathBuf,
+    size: u64,
+    hash: blake3::Hash,
+    isbn: Option<String>,
+    title_author: Option<String>,
+}
+
+/// Collects every discovered ebook and groups the duplicates among them
+#[derive(Default)]
+pub struct DuplicateFinder {
+    candidates: Vec<Candidate>,
+}
+
+impl DuplicateFinder {
+    /// Record an ebook
+    pub fn add(&mut self, path: &Path, size: u64, hash: blake3::Hash, book: &BookMetadata) {
+        let isbn = book.isbn().and_then(|isbn| isbn13(&isbn.value));
+        let title_author = book
+            .title()
+            .filter(|_| !book.authors.is_empty())
+            .map(|title| {
+                let authors = book
+                    .authors
+                    .iter()
+                    .map(|author| comparison_key(&author.name))
+                    .collect::<Vec<_>>();
+                format!("{}/{}", comparison_key(title), authors.join("+"))
+            });
+
+        self.candidates.push(Candidate {
+            path: path.to_path_buf(),
+            size,
+            hash,
+            isbn,
+            title_author,
+        });
+    }
+
+    /// Group the duplicates and decide what happens to each copy. Every file is a copy in at most
+    /// one group, so no file is dropped twice.
+    pub fn groups(
+        mut self,
+        policy: DuplicatePolicy,
+        directory: Option<&Path>,
+    ) -> Vec<DuplicateGroup> {
+        self.candidates.sort_by(|a, b| a.path.cmp(&b.path));
+        let files = distinct_files(&self.candidates);
+
+        let mut groups = Vec::new();
+        let mut copies = HashSet::new();
+
+        // Exact copies are grouped first, then only the originals are compared by ISBN, and the
+        // originals left after that by title and author
+        let exact = group_by(files.iter().copied(), |c| Some(c.hash.to_hex().to_string()));
+        for (hash, members) in exact {
+            groups.push(resolve(
+                DuplicateKind::Exact(hash),
+                members,
+                policy,
+                directory,
+            ));
+        }
+        copies.extend(groups.iter().flat_map(DuplicateGroup::copies));
+
+        let originals = |copies: &HashSet<PathBuf>| {
+            files
+                .iter()
+                .copied()
+                .filter(|c| !copies.contains(&c.path))
+                .collect::<Vec<_>>()
+        };
+
+        let isbn_groups = group_by(originals(&copies), |c| c.isbn.clone())
+            .into_iter()
+            .map(|(isbn, members)| resolve(DuplicateKind::Isbn(isbn), members, policy, directory))
+            .collect::<Vec<_>>();
+        copies.extend(isbn_groups.iter().flat_map(DuplicateGroup::copies));
+        groups.extend(isbn_groups);
+
+        for (key, members) in group_by(originals(&copies), |c| c.title_author.clone()) {
+            groups.push(resolve(
+                DuplicateKind::TitleAuthor(key),
+                members,
+                policy,
+                directory,
+            ));
+        }
+
+        groups
+    }
+}
+
+/// The candidates in path order, leaving out any which lead to the same file as another, such as
+/// a link placed by an earlier run next to the file it points to. The file itself is kept over
+/// a symbolic link to it, so that it is never moved away from under the link.
+fn distinct_files(candidates: &[Candidate]) -> Vec<&Candidate> {
+    let mut ordered = candidates.iter().collect::<Vec<_>>();
+    ordered.sort_by_key(|c| c.path.is_symlink());
+
+    // The same file always has the same hash, so only candidates sharing one are compared
+    let mut by_hash: HashMap<blake3::Hash, Vec<&Candidate>> = HashMap::new();
+    let mut files = Vec::new();
+    for candidate in ordered {
+        let seen = by_hash.entry(candidate.hash).or_default();
+        if !seen
+            .iter()
+            .any(|file| place::same_file(&file.path, &candidate.path))
+        {
+            seen.push(candidate);
+            files.push(candidate);
+        }
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    files
+}
+
+/// Group candidates sharing a key, keeping only the groups with more than one member
+fn group_by<'a>(
+    candidates: impl IntoIterator<Item = &'a Candidate>,
+    key: impl Fn(&Candidate) -> Option<String>,
+) -> Vec<(String, Vec<&'a Candidate>)> {
+    let mut groups: BTreeMap<String, Vec<&Candidate>> = BTreeMap::new();
+    for candidate in candidates {
+        if let Some(key) = key(candidate) {
+            groups.entry(key).or_default().push(candidate);
+        }
+    }
+    groups
+        .into_iter()
+        .filter(|(_, members)| members.len() > 1)
+        .collect()
+}
+
+/// Pick the copy to keep and decide what happens to the others
+fn resolve(
+    kind: DuplicateKind,
+    members: Vec<&Candidate>,
+    policy: DuplicatePolicy,
+    directory: Option<&Path>,
+) -> DuplicateGroup {
+    // Members are in path order, and the largest copy wins ties by coming first
+    let keep = match policy {
+        DuplicatePolicy::KeepLargest => members
+            .iter()
+            .enumerate()
+            .max_by_key(|(i, c)| (c.size, std::cmp::Reverse(*i)))
+            .map_or(0, |(i, _)| i),
+        _ => 0,
+    };
+    let keep_path = members[keep].path.clone();
+
+    let members = members
+        .into_iter()
+        .enumerate()
+        .map(|(i, candidate)| {
+            let action = match (policy, directory) {
+                _ if i == keep => DuplicateAction::Place,
+                (DuplicatePolicy::Report, _) => DuplicateAction::Place,
+                (DuplicatePolicy::MoveTo, Some(directory)) => DuplicateAction::MoveTo(
+                    directory.join(candidate.path.file_name().unwrap_or_default()),
+                ),
+                _ => DuplicateAction::Skip,
+            };
+            DuplicateMember {
+                path: candidate.path.clone(),
+                size: candidate.size,
+                action,
+            }
+        })
+        .collect();
+
+    DuplicateGroup {
+        kind,
+        keep: keep_path,
+        members,
+    }
+}
+
+/// Normalize an ISBN to ISBN-13, so that the ISBN-10 and ISBN-13 forms of a book match
+fn isbn13(isbn: &str) -> Option<String> {
+    let isbn = isbn
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect::<String>();
+
+    match isbn.len() {
+        13 if isbn.chars().all(|c| c.is_ascii_digit()) => Some(isbn),
+        10 if isbn[..9].chars().all(|c| c.is_ascii_digit()) => {
+            let body = format!("978{}", &isbn[..9]);
+            let sum = body
+                .chars()
+                .filter_map(|c| c.to_digit(10))
+                .enumerate()
+                .map(|(i, digit)| if i % 2 == 0 { digit } else { digit * 3 })
+                .sum::<u32>();
+            Some(format!("{body}{}", (10 - sum % 10) % 10))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(title: &str, author: &str) -> BookMetadata {
+        BookMetadata {
+            titles: vec![title.to_string()],
+            authors: vec![crate::metadata::Contributor::new(author)],
+            ..Default::default()
+        }
+    }
+
+    fn add(finder: &mut DuplicateFinder, path: &Path) {
+        let size = path.metadata().unwrap().len();
+        let hash = crate::hash::hash_file(path).unwrap();
+        finder.add(path, size, hash, &book("Dune", "Frank Herbert"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_next_to_its_target_is_not_a_duplicate() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("b.epub");
+        std::fs::write(&target, "dune").unwrap();
+        // Sorts before its target, so it would be kept and the target moved away
+        let link = dir.path().join("A - Dune.epub");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        let mut finder = DuplicateFinder::default();
+        add(&mut finder, &link);
+        add(&mut finder, &target);
+
+        let groups = finder.groups(DuplicatePolicy::MoveTo, Some(&dir.path().join("dups")));
+        assert!(groups.is_empty(), "{groups:?}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copy_of_a_linked_file_keeps_the_file_itself() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("b.epub");
+        std::fs::write(&target, "dune").unwrap();
+        let link = dir.path().join("A - Dune.epub");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let copy = dir.path().join("c.epub");
+        std::fs::write(&copy, "dune").unwrap();
+
+        let mut finder = DuplicateFinder::default();
+        for path in [&link, &target, &copy] {
+            add(&mut finder, path);
+        }
+
+        let groups = finder.groups(DuplicatePolicy::KeepFirst, None);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].kind, DuplicateKind::Exact(hash_of(&target)));
+        assert_eq!(groups[0].keep, target);
+        let paths = groups[0]
+            .members
+            .iter()
+            .map(|m| &m.path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, [&target, &copy]);
+    }
+
+    fn hash_of(path: &Path) -> String {
+        crate::hash::hash_file(path).unwrap().to_hex().to_string()
+    }
+}
@@ -0,0 +1,100 @@
+use serde::Serialize;
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+/// The exit code of a run which completed, but failed to read or place some ebooks. Fatal
+/// errors exit with 1 and invalid arguments with 2.
+pub const EXIT_PARTIAL: u8 = 3;
+
+/// The step of the pipeline an ebook was in when it failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Reading the file's size, modification time or contents
+    Scan,
+    /// Extracting the ebook's metadata
+    Parse,
+    /// Creating the directories leading to the destination
+    Mkdir,
+    /// Placing the ebook at its destination
+    Place,
+    /// Rewriting the metadata inside the placed ebook
+    WriteMetadata,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Scan => write!(f, "scan"),
+            Stage::Parse => write!(f, "parse"),
+            Stage::Mkdir => write!(f, "mkdir"),
+            Stage::Place => write!(f, "place"),
+            Stage::WriteMetadata => write!(f, "write metadata"),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EbookSortError {
+    #[error("Failed to read metadata from ebook")]
+    InvalidEbook { path: PathBuf, error: String },
+    #[error("Failed to perform IO operation during {stage}: {error}")]
+    IoError {
+        stage: Stage,
+        path: PathBuf,
+        /// Where the ebook was going, once it is known
+        destination: Option<PathBuf>,
+        #[source]
+        error: io::Error,
+    },
+}
+
+impl EbookSortError {
+    pub fn io(
+        stage: Stage,
+        path: &Path,
+        destination: Option<&Path>,
+        error: io::Error,
+    ) -> EbookSortError {
+        EbookSortError::IoError {
+            stage,
+            path: path.to_path_buf(),
+            destination: destination.map(Path::to_path_buf),
+            error,
+        }
+    }
+
+    /// The path of the ebook which caused the error
+    pub fn path(&self) -> &Path {
+        match self {
+            EbookSortError::InvalidEbook { path, .. } | EbookSortError::IoError { path, .. } => {
+                path
+            }
+        }
+    }
+
+    /// Where the ebook would have been placed, if the error happened after it was planned
+    pub fn destination(&self) -> Option<&Path> {
+        match self {
+            EbookSortError::InvalidEbook { .. } => None,
+            EbookSortError::IoError { destination, .. } => destination.as_deref(),
+        }
+    }
+
+    pub fn stage(&self) -> Stage {
+        match self {
+            EbookSortError::InvalidEbook { .. } => Stage::Parse,
+            EbookSortError::IoError { stage, .. } => *stage,
+        }
+    }
+
+    /// A human-readable description of the error, suitable for a report row
+    pub fn message(&self) -> String {
+        match self {
+            EbookSortError::InvalidEbook { error, .. } => error.clone(),
+            EbookSortError::IoError { error, .. } => error.to_string(),
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use regex::Regex;
+use serde::Serialize;
+use std::{collections::BTreeMap, fmt, str::FromStr, sync::LazyLock};
+
+use crate::metadata::{parse_index, BookMetadata, Contributor};
+
+/// Common ways of naming ebook files, tried after the user's own patterns
+const BUILTIN_PATTERNS: [&str; 5] = [
+    // `Frank Herbert - Dune 01 - Dune`
+    r"^(?P<author>[^-]+?) - (?P<series>.+?),? #?(?P<series_index>\d+(?:\.\d+)?) - (?P<title>.+)$",
+    // `Frank Herbert - [Dune 01] - Dune`
+    r"^(?P<author>[^-]+?) - [\[(](?P<series>.+?),? #?(?P<series_index>\d+(?:\.\d+)?)[\])] - (?P<title>.+)$",
+    // `Frank Herbert - Dune (Dune #1)`
+    r"^(?P<author>[^-]+?) - (?P<title>.+?) \((?P<series>.+?),? #?(?P<series_index>\d+(?:\.\d+)?)\)$",
+    // `Frank Herbert - Dune (1965)`
+    r"^(?P<author>[^-]+?) - (?P<title>.+?) \((?P<year>\d{4})\)$",
+    // `Frank Herbert - Dune`
+    r"^(?P<author>[^-]+?) - (?P<title>.+)$",
+];
+
+static BUILTINS: LazyLock<Vec<FilenamePattern>> = LazyLock::new(|| {
+    BUILTIN_PATTERNS
+        .iter()
+        .map(|pattern| pattern.parse().expect("the built-in patterns are valid"))
+        .collect()
+});
+
+/// A piece of metadata which can be read from a file name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    Title,
+    Author,
+    Series,
+    SeriesIndex,
+    Year,
+}
+
+impl Field {
+    const ALL: [Field; 5] = [
+        Field::Title,
+        Field::Author,
+        Field::Series,
+        Field::SeriesIndex,
+        Field::Year,
+    ];
+
+    /// The name of the capture group which provides the field
+    fn group(self) -> &'static str {
+        match self {
+            Field::Title => "title",
+            Field::Author => "author",
+            Field::Series => "series",
+            Field::SeriesIndex => "series_index",
+            Field::Year => "year",
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.group())
+    }
+}
+
+/// Where the value of a field used to sort a book came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldSource {
+    /// The metadata embedded in the ebook, such as the EPUB's OPF
+    Metadata,
+    /// A filename pattern, because the ebook's metadata left the field out
+    Filename,
+}
+
+/// The source of every field a book has a value for
+pub type FieldSources = BTreeMap<Field, FieldSource>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PatternError {
+    #[error("{0}")]
+    Regex(#[from] regex::Error),
+    #[error("Unknown group '{0}', expected one of: title, author, series, series_index, year")]
+    UnknownGroup(String),
+    #[error("The pattern has no named groups, e.g. (?P<author>.+) - (?P<title>.+)")]
+    NoGroups,
+}
+
+/// A regular expression matched against the stem of an ebook's file name, with named groups
+/// such as `(?P<author>.+) - (?P<title>.+)` capturing the fields it provides
+#[derive(Debug, Clone)]
+pub struct FilenamePattern {
+    regex: Regex,
+}
+
+impl FilenamePattern {
+    /// The fields captured from `stem`, if the pattern matches it
+    fn captures(&self, stem: &str) -> Option<Vec<(Field, String)>> {
+        let captures = self.regex.captures(stem)?;
+        // Names always have a letter, so `01 - Dune`, as written by the default template for a
+        // book without an author, doesn't credit it to `01`
+        if captures
+            .name(Field::Author.group())
+            .is_some_and(|author| !author.as_str().chars().any(char::is_alphabetic))
+        {
+            return None;
+        }
+        let fields = Field::ALL
+            .into_iter()
+            .filter_map(|field| {
+                let value = captures.name(field.group())?.as_str().trim();
+                (!value.is_empty()).then(|| (field, value.to_string()))
+            })
+            .collect();
+        Some(fields)
+    }
+}
+
+impl FromStr for FilenamePattern {
+    type Err = PatternError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let regex = Regex::new(s)?;
+        let mut names = regex.capture_names().flatten().peekable();
+        if names.peek().is_none() {
+            return Err(PatternError::NoGroups);
+        }
+        if let Some(unknown) = names.find(|name| !Field::ALL.iter().any(|f| f.group() == *name)) {
+            return Err(PatternError::UnknownGroup(unknown.to_string()));
+        }
+        Ok(FilenamePattern { regex })
+    }
+}
+
+impl fmt::Display for FilenamePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.regex.as_str())
+    }
+}
+
+/// Fill in the fields `metadata` is missing from the first pattern which matches the file name's
+/// stem, trying `patterns` and then, unless turned off, the built-in ones
+pub fn fill_missing(
+    metadata: &mut BookMetadata,
+    stem: &str,
+    patterns: &[FilenamePattern],
+    builtins: bool,
+) -> FieldSources {
+    let mut sources = FieldSources::new();
+    if Field::ALL.iter().any(|&field| missing(metadata, field)) {
+        let builtins = match builtins {
+            true => BUILTINS.as_slice(),
+            false => &[],
+        };
+        let captured = patterns
+            .iter()
+            .chain(builtins)
+            .find_map(|pattern| pattern.captures(stem.trim()));
+
+        for (field, value) in captured.unwrap_or_default() {
+            if !missing(metadata, field) {
+                continue;
+            }
+            match field {
+                Field::Title => metadata.titles.push(value),
+                Field::Author => {
+                    metadata.authors = value
+                        .split([';', '&'])
+                        .map(str::trim)
+                        .filter(|name| !name.is_empty())
+                        .map(Contributor::new)
+                        .collect()
+                }
+                // An index only makes sense within the series it was numbered in, so the two are
+                // only ever taken from the file name together
+                Field::Series if metadata.series_index.is_some() => continue,
+                Field::Series => metadata.series = Some(value),
+                Field::SeriesIndex
+                    if sources.get(&Field::Series) != Some(&FieldSource::Filename) =>
+                {
+                    continue
+                }
+                Field::SeriesIndex => match parse_index(&value) {
+                    Some(index) => metadata.series_index = Some(index),
+                    None => continue,
+                },
+                Field::Year => metadata.published = Some(value),
+            }
+            sources.insert(field, FieldSource::Filename);
+        }
+    }
+
+    for (field, source) in self::sources(metadata) {
+        sources.entry(field).or_insert(source);
+    }
+    sources
+}
+
+/// The sources of a book's fields when they all come from its metadata
+pub fn sources(metadata: &BookMetadata) -> FieldSources {
+    Field::ALL
+        .into_iter()
+        .filter(|&field| !missing(metadata, field))
+        .map(|field| (field, FieldSource::Metadata))
+        .collect()
+}
+
+fn missing(metadata: &BookMetadata, field: Field) -> bool {
+    match field {
+        Field::Title => metadata.title().is_none(),
+        Field::Author => metadata.authors.is_empty(),
+        Field::Series => metadata.series.is_none(),
+        Field::SeriesIndex => metadata.series_index.is_none(),
+        Field::Year => metadata.year().is_none(),
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+    process::Command,
+};
+
+use super::{push, FormatReader, RawMetadata, ReadError};
+use crate::metadata::BookMetadata;
+
+const COMIC_INFO: &str = "ComicInfo.xml";
+
+pub struct CbzReader;
+
+impl FormatReader for CbzReader {
+    fn read(&self, path: &Path) -> Result<BookMetadata, ReadError> {
+        let mut archive = zip::ZipArchive::new(File::open(path)?)
+            .map_err(|e| ReadError::Invalid(e.to_string()))?;
+
+        let Some(name) = archive
+            .file_names()
+            .find(|name| is_comic_info(name))
+            .map(str::to_string)
+        else {
+            return Ok(BookMetadata::default());
+        };
+
+        let mut xml = String::new();
+        archive
+            .by_name(&name)
+            .map_err(|e| ReadError::Invalid(e.to_string()))?
+            .read_to_string(&mut xml)?;
+
+        parse_comic_info(&xml)
+    }
+}
+
+/// RAR archives are read with whichever of these tools is installed, as there is no
+/// pure-Rust RAR decompressor. Each prints the matching archive member to stdout.
+const CBR_EXTRACTORS: &[(&str, &[&str], &[&str])] = &[
+    ("unrar", &["p", "-inul"], &[COMIC_INFO, "*/ComicInfo.xml"]),
+    ("bsdtar", &["-xOf"], &["*ComicInfo.xml"]),
+];
+
+pub struct CbrReader;
+
+impl FormatReader for CbrReader {
+    fn read(&self, path: &Path) -> Result<BookMetadata, ReadError> {
+        for (program, args, patterns) in CBR_EXTRACTORS {
+            let output = match Command::new(program)
+                .args(*args)
+                .arg(path)
+                .args(*patterns)
+                .output()
+            {
+                Ok(output) => output,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            let xml = String::from_utf8_lossy(&output.stdout);
+            if !xml.trim().is_empty() {
+                return parse_comic_info(&xml);
+            }
+
+            // Both tools fail when nothing matches, which just means there is no metadata
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return if output.status.success() || stderr.is_empty() || stderr.contains("Not found") {
+                Ok(BookMetadata::default())
+            } else {
+                Err(ReadError::Invalid(stderr.trim().to_string()))
+            };
+        }
+
+        Err(ReadError::Invalid(
+            "Reading CBR files requires `unrar` or `bsdtar` to be installed".into(),
+        ))
+    }
+}
+
+fn is_comic_info(name: &str) -> bool {
+    name.rsplit('/')
+        .next()
+        .is_some_and(|file| file.eq_ignore_ascii_case(COMIC_INFO))
+}
+
+fn parse_comic_info(xml: &str) -> Result<BookMetadata, ReadError> {
+    let document = roxmltree::Document::parse(xml.trim_start_matches('\u{feff}'))
+        .map_err(|e| ReadError::Invalid(format!("Invalid {COMIC_INFO}: {e}")))?;
+
+    let mut metadata = RawMetadata::new();
+    let mut year = None;
+    let mut month = None;
+    let mut day = None;
+
+    for node in document
+        .root_element()
+        .children()
+        .filter(|n| n.is_element())
+    {
+        let Some(text) = node.text().map(str::trim).filter(|t| !t.is_empty()) else {
+            continue;
+        };
+        match node.tag_name().name() {
+            "Title" => push(&mut metadata, "title", text),
+            "Series" => push(&mut metadata, "calibre:series", text),
+            "Number" => push(&mut metadata, "calibre:series_index", text),
+            "Writer" => text
+                .split(',')
+                .for_each(|writer| push(&mut metadata, "creator", writer)),
+            "Penciller" | "Inker" | "Colorist" | "Letterer" | "CoverArtist" | "Editor" => text
+                .split(',')
+                .for_each(|artist| push(&mut metadata, "contributor", artist)),
+            "Publisher" => push(&mut metadata, "publisher", text),
+            "Summary" => push(&mut metadata, "description", text),
+            "Genre" | "Tags" => text
+                .split(',')
+                .for_each(|subject| push(&mut metadata, "subject", subject)),
+            "LanguageISO" => push(&mut metadata, "language", text),
+            "GTIN" => push(&mut metadata, "identifier", text),
+            "Web" => push(&mut metadata, "source", text),
+            "Year" => year = text.parse::<u32>().ok(),
+            "Month" => month = text.parse::<u32>().ok(),
+            "Day" => day = text.parse::<u32>().ok(),
+            _ => {}
+        }
+    }
+
+    let date = match (year, month, day) {
+        (Some(year), Some(month), Some(day)) => Some(format!("{year:04}-{month:02}-{day:02}")),
+        (Some(year), Some(month), None) => Some(format!("{year:04}-{month:02}")),
+        (Some(year), _, _) => Some(format!("{year:04}")),
+        _ => None,
+    };
+    if let Some(date) = date {
+        push(&mut metadata, "date", date);
+    }
+
+    // Single issues often only carry a series and number, which make a sensible title
+    if !metadata.contains_key("title") {
+        if let Some(series) = metadata.get("calibre:series").and_then(|s| s.first()) {
+            let title = match metadata.get("calibre:series_index").and_then(|n| n.first()) {
+                Some(number) => format!("{series} #{number}"),
+                None => series.clone(),
+            };
+            push(&mut metadata, "title", title);
+        }
+    }
+
+    Ok(BookMetadata::from_raw(metadata))
+}
@@ -0,0 +1,217 @@
+use epub::doc::EpubDoc;
+use roxmltree::Node;
+use std::{collections::HashMap, path::Path};
+
+use super::{FormatReader, ReadError};
+use crate::metadata::{parse_index, BookMetadata, Contributor, Identifier};
+
+pub(super) const DC: &str = "http://purl.org/dc/elements/1.1/";
+
+pub struct EpubReader;
+
+impl FormatReader for EpubReader {
+    fn read(&self, path: &Path) -> Result<BookMetadata, ReadError> {
+        let mut book = EpubDoc::new(path).map_err(|e| ReadError::Invalid(e.to_string()))?;
+        let root_file = book.root_file.clone();
+
+        // The epub crate flattens the metadata into plain strings, dropping the attributes and
+        // refinements which carry roles and sort names, so the package document is read directly
+        match book.get_resource_str_by_path(&root_file) {
+            Some(opf) => parse_opf(&opf),
+            None => Ok(BookMetadata::from_raw(book.metadata)),
+        }
+    }
+}
+
+/// A property attached to an element by an EPUB3 `<meta refines="#id">`
+struct Refinement<'a> {
+    property: &'a str,
+    scheme: Option<&'a str>,
+    value: String,
+}
+
+/// Build metadata from an OPF package document, understanding both the EPUB2 `opf:` attributes
+/// and EPUB3 `meta refines` semantics
+pub(super) fn parse_opf(opf: &str) -> Result<BookMetadata, ReadError> {
+    let document = roxmltree::Document::parse(opf.trim_start_matches('\u{feff}'))
+        .map_err(|e| ReadError::Invalid(format!("Invalid package document: {e}")))?;
+    let Some(metadata_node) = document
+        .root_element()
+        .children()
+        .find(|n| n.is_element() && n.tag_name().name() == "metadata")
+    else {
+        return Err(ReadError::Invalid(
+            "Package document has no metadata".into(),
+        ));
+    };
+
+    let elements = metadata_node
+        .descendants()
+        .filter(|n| n.is_element())
+        .collect::<Vec<_>>();
+
+    let mut refinements: HashMap<&str, Vec<Refinement>> = HashMap::new();
+    for node in &elements {
+        if let (Some(target), Some(property)) =
+            (node.attribute("refines"), node.attribute("property"))
+        {
+            refinements
+                .entry(target.trim_start_matches('#'))
+                .or_default()
+                .push(Refinement {
+                    property,
+                    scheme: node.attribute("scheme"),
+                    value: text(*node),
+                });
+        }
+    }
+    let refined = |node: &Node, property: &str| -> Vec<&Refinement> {
+        node.attribute("id")
+            .and_then(|id| refinements.get(id))
+            .map(|r| r.iter().filter(|r| r.property == property).collect())
+            .unwrap_or_default()
+    };
+
+    let mut metadata = BookMetadata::default();
+    let mut titles = Vec::new();
+    let mut dates = Vec::new();
+    let mut calibre_series = None;
+    let mut calibre_index = None;
+    let mut collections = Vec::new();
+
+    for node in &elements {
+        let value = text(*node);
+
+        if node.tag_name().namespace() != Some(DC) {
+            if node.tag_name().name() == "meta" {
+                let content = node.attribute("content").map(str::trim).unwrap_or_default();
+                match (node.attribute("name"), node.attribute("property")) {
+                    (Some("calibre:series"), _) if !content.is_empty() => {
+                        calibre_series = Some(content.to_string())
+                    }
+                    (Some("calibre:series_index"), _) => calibre_index = parse_index(content),
+                    (_, Some("belongs-to-collection")) if !value.is_empty() => {
+                        let collection_type = refined(node, "collection-type")
+                            .first()
+                            .map(|r| r.value.clone());
+                        let position = refined(node, "group-position")
+                            .first()
+                            .and_then(|r| parse_index(&r.value));
+                        collections.push((collection_type, value, position));
+                    }
+                    _ => {}
+                }
+            }
+            continue;
+        }
+        if value.is_empty() {
+            continue;
+        }
+
+        match node.tag_name().name() {
+            "title" => {
+                let title_type = refined(node, "title-type")
+                    .first()
+                    .map(|r| r.value.clone())
+                    .unwrap_or_default();
+                let display_seq = refined(node, "display-seq")
+                    .first()
+                    .and_then(|r| r.value.parse::<u32>().ok())
+                    .unwrap_or(u32::MAX);
+                titles.push((title_type != "main", display_seq, value));
+            }
+            name @ ("creator" | "contributor") => {
+                let file_as = attribute(node, "file-as")
+                    .map(str::to_string)
+                    .or_else(|| refined(node, "file-as").first().map(|r| r.value.clone()))
+                    .filter(|f| !f.is_empty());
+                let mut roles = attribute(node, "role")
+                    .map(|role| vec![role.trim().to_string()])
+                    .unwrap_or_default();
+                roles.extend(refined(node, "role").iter().map(|r| r.value.clone()));
+
+                let contributor = Contributor {
+                    name: value,
+                    file_as,
+                    roles,
+                };
+                if name == "creator" {
+                    metadata.authors.push(contributor);
+                } else {
+                    metadata.contributors.push(contributor);
+                }
+            }
+            "identifier" => {
+                let scheme = attribute(node, "scheme").map(str::to_string).or_else(|| {
+                    refined(node, "identifier-type")
+                        .first()
+                        .map(|r| onix_identifier_type(r.scheme, &r.value))
+                });
+                metadata
+                    .identifiers
+                    .push(Identifier::new(scheme.as_deref(), &value));
+            }
+            "date" => {
+                let event = attribute(node, "event").unwrap_or("publication");
+                dates.push((event != "publication", value));
+            }
+            "language" => metadata.languages.push(value),
+            "publisher" => metadata.publisher = metadata.publisher.or(Some(value)),
+            "subject" => metadata.subjects.push(value),
+            "description" => metadata.description = metadata.description.or(Some(value)),
+            other => metadata
+                .other
+                .entry(other.to_string())
+                .or_default()
+                .push(value),
+        }
+    }
+
+    // Main titles and publication dates first, otherwise keeping document order
+    titles.sort_by_key(|(not_main, seq, _)| (*not_main, *seq));
+    metadata.titles = titles.into_iter().map(|(_, _, title)| title).collect();
+    dates.sort_by_key(|(not_publication, _)| *not_publication);
+    metadata.published = dates.into_iter().next().map(|(_, date)| date);
+
+    // calibre's series is explicit, otherwise use the first EPUB3 collection which is a series,
+    // or failing that one without a type. Collections typed as a `set` are not ordered series.
+    if calibre_series.is_some() {
+        metadata.series = calibre_series;
+        metadata.series_index = calibre_index;
+    } else if let Some((_, series, position)) = collections
+        .iter()
+        .find(|(kind, _, _)| kind.as_deref() == Some("series"))
+        .or_else(|| collections.iter().find(|(kind, _, _)| kind.is_none()))
+    {
+        metadata.series = Some(series.clone());
+        metadata.series_index = *position;
+    }
+
+    Ok(metadata)
+}
+
+/// Look up an attribute by local name, as `opf:` attributes are not always namespaced properly
+fn attribute<'a>(node: &Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.attributes()
+        .find(|a| a.name() == name)
+        .map(|a| a.value())
+}
+
+fn text(node: Node) -> String {
+    node.text()
+        .unwrap_or_default()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Translate an EPUB3 `identifier-type` refinement into a scheme name. ONIX code list 5 is the
+/// scheme most publishers use, where `02` and `15` are ISBN-10 and ISBN-13.
+fn onix_identifier_type(scheme: Option<&str>, value: &str) -> String {
+    match (scheme, value) {
+        (Some("onix:codelist5"), "02" | "15") => "isbn".to_string(),
+        (Some("onix:codelist5"), "22") => "urn".to_string(),
+        (Some("onix:codelist5"), "06") => "doi".to_string(),
+        _ => value.to_lowercase(),
+    }
+}
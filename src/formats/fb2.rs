@@ -0,0 +1,128 @@
+use roxmltree::Node;
+use std::path::Path;
+
+use super::{push, FormatReader, RawMetadata, ReadError};
+use crate::metadata::BookMetadata;
+
+pub struct Fb2Reader;
+
+impl FormatReader for Fb2Reader {
+    fn read(&self, path: &Path) -> Result<BookMetadata, ReadError> {
+        let data = std::fs::read(path)?;
+        let xml = decode(&data);
+
+        // Only the description is needed, so the (potentially huge and often malformed) body
+        // and embedded binaries are cut off before parsing
+        let xml = match xml.find("</description>") {
+            Some(end) => format!("{}</description></FictionBook>", &xml[..end]),
+            None => xml,
+        };
+
+        let document = roxmltree::Document::parse(&xml)
+            .map_err(|e| ReadError::Invalid(format!("Invalid FB2 document: {e}")))?;
+        let description = child(document.root_element(), "description")
+            .ok_or_else(|| ReadError::Invalid("FB2 document has no description".into()))?;
+
+        let mut metadata = RawMetadata::new();
+
+        if let Some(title_info) = child(description, "title-info") {
+            for node in title_info.children().filter(|n| n.is_element()) {
+                match node.tag_name().name() {
+                    "book-title" => push(&mut metadata, "title", text(node)),
+                    "author" => push(&mut metadata, "creator", author_name(node)),
+                    "translator" => push(&mut metadata, "contributor", author_name(node)),
+                    "genre" => push(&mut metadata, "subject", text(node)),
+                    "keywords" => text(node)
+                        .split(',')
+                        .for_each(|keyword| push(&mut metadata, "subject", keyword)),
+                    "annotation" => push(&mut metadata, "description", text(node)),
+                    "lang" => push(&mut metadata, "language", text(node)),
+                    "date" => push(
+                        &mut metadata,
+                        "date",
+                        node.attribute("value")
+                            .map(str::to_string)
+                            .unwrap_or(text(node)),
+                    ),
+                    "sequence" => {
+                        if let Some(name) = node.attribute("name") {
+                            push(&mut metadata, "calibre:series", name);
+                            if let Some(number) = node.attribute("number") {
+                                push(&mut metadata, "calibre:series_index", number);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if let Some(publish_info) = child(description, "publish-info") {
+            for node in publish_info.children().filter(|n| n.is_element()) {
+                match node.tag_name().name() {
+                    "publisher" => push(&mut metadata, "publisher", text(node)),
+                    "isbn" => push(&mut metadata, "identifier", text(node)),
+                    "year" if !metadata.contains_key("date") => {
+                        push(&mut metadata, "date", text(node))
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if let Some(id) = child(description, "document-info").and_then(|n| child(n, "id")) {
+            push(&mut metadata, "identifier", text(id));
+        }
+
+        Ok(BookMetadata::from_raw(metadata))
+    }
+}
+
+/// Decode the document using the encoding from its XML declaration, as FB2 files are
+/// frequently stored as windows-1251 rather than UTF-8
+fn decode(data: &[u8]) -> String {
+    let head = String::from_utf8_lossy(&data[..data.len().min(200)]);
+    let declared = head
+        .split_once("encoding=")
+        .and_then(|(_, rest)| {
+            let quote = rest.chars().next()?;
+            rest.get(quote.len_utf8()..)?.split(quote).next()
+        })
+        .and_then(|label| encoding_rs::Encoding::for_label(label.as_bytes()));
+
+    let (text, _, _) = declared.unwrap_or(encoding_rs::UTF_8).decode(data);
+    // The declaration no longer matches once decoded, and roxmltree ignores it anyway
+    text.into_owned()
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children()
+        .find(|n| n.is_element() && n.tag_name().name() == name)
+}
+
+/// All of the text inside a node, with whitespace collapsed
+fn text(node: Node) -> String {
+    node.descendants()
+        .filter(|n| n.is_text())
+        .filter_map(|n| n.text())
+        .collect::<Vec<_>>()
+        .join(" ")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn author_name(node: Node) -> String {
+    let parts = ["first-name", "middle-name", "last-name"]
+        .iter()
+        .filter_map(|name| child(node, name))
+        .map(text)
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>();
+
+    if parts.is_empty() {
+        child(node, "nickname").map(text).unwrap_or_default()
+    } else {
+        parts.join(" ")
+    }
+}
@@ -0,0 +1,108 @@
+use std::path::Path;
+
+use super::{push, FormatReader, RawMetadata, ReadError};
+use crate::metadata::BookMetadata;
+
+/// Offset of the record count in the PalmDB header
+const PDB_RECORD_COUNT: usize = 76;
+/// Offset of the record list in the PalmDB header
+const PDB_RECORD_LIST: usize = 78;
+/// Size of the PalmDOC header which precedes the MOBI header in record 0
+const PALMDOC_HEADER_LEN: usize = 16;
+/// Set in the MOBI header's EXTH flags when an EXTH header follows it
+const EXTH_PRESENT: u32 = 0x40;
+const UTF8: u32 = 65001;
+
+pub struct MobiReader;
+
+impl FormatReader for MobiReader {
+    fn read(&self, path: &Path) -> Result<BookMetadata, ReadError> {
+        let data = std::fs::read(path)?;
+        parse(&data)
+            .map(BookMetadata::from_raw)
+            .ok_or_else(|| ReadError::Invalid("Invalid or truncated MOBI header".into()))
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn parse(data: &[u8]) -> Option<RawMetadata> {
+    if u16_at(data, PDB_RECORD_COUNT)? == 0 {
+        return None;
+    }
+    let record0 = u32_at(data, PDB_RECORD_LIST)? as usize;
+    let mobi = record0 + PALMDOC_HEADER_LEN;
+    if data.get(mobi..mobi + 4)? != b"MOBI" {
+        return None;
+    }
+
+    let header_len = u32_at(data, mobi + 4)? as usize;
+    let encoding = u32_at(data, mobi + 12)?;
+    let decode = |bytes: &[u8]| -> String {
+        if encoding == UTF8 {
+            String::from_utf8_lossy(bytes).to_string()
+        } else {
+            encoding_rs::WINDOWS_1252.decode(bytes).0.to_string()
+        }
+    };
+
+    let mut metadata = RawMetadata::new();
+
+    let exth_flags = u32_at(data, mobi + 0x70).unwrap_or_default();
+    if exth_flags & EXTH_PRESENT != 0 {
+        let exth = mobi + header_len;
+        if data.get(exth..exth + 4) == Some(b"EXTH") {
+            let count = u32_at(data, exth + 8)?;
+            let mut offset = exth + 12;
+            for _ in 0..count {
+                let kind = u32_at(data, offset)?;
+                let len = u32_at(data, offset + 4)? as usize;
+                let value = data.get(offset + 8..offset + len.max(8))?;
+                let key = match kind {
+                    100 => Some("creator"),
+                    101 => Some("publisher"),
+                    103 => Some("description"),
+                    104 => Some("identifier"),
+                    105 => Some("subject"),
+                    106 => Some("date"),
+                    108 => Some("contributor"),
+                    109 => Some("rights"),
+                    503 => Some("title"),
+                    524 => Some("language"),
+                    _ => None,
+                };
+                if let Some(key) = key {
+                    push(&mut metadata, key, decode(value));
+                }
+                offset += len.max(8);
+            }
+        }
+    }
+
+    // The full name in the MOBI header is only used when EXTH has no updated title
+    if !metadata.contains_key("title") {
+        let name_offset = u32_at(data, mobi + 0x44)? as usize;
+        let name_len = u32_at(data, mobi + 0x48)? as usize;
+        if let Some(name) = data.get(record0 + name_offset..record0 + name_offset + name_len) {
+            push(&mut metadata, "title", decode(name));
+        }
+    }
+
+    if let Some(dates) = metadata.get_mut("date") {
+        for date in dates.iter_mut() {
+            *date = date.chars().take(10).collect();
+        }
+    }
+
+    Some(metadata)
+}
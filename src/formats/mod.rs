@@ -0,0 +1,100 @@
+use clap::ValueEnum;
+use std::{collections::HashMap, io, path::Path};
+
+use crate::metadata::BookMetadata;
+
+mod comic;
+mod epub;
+mod fb2;
+mod mobi;
+mod opf;
+mod pdf;
+
+pub use opf::write_metadata;
+
+/// Metadata keyed by Dublin Core element names (`title`, `creator`, `language`, ...), the same
+/// shape `EpubDoc::metadata` exposes. Readers for formats without roles or sort names map their
+/// native fields onto these keys and convert them with [`BookMetadata::from_raw`].
+pub type RawMetadata = HashMap<String, Vec<String>>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReadError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Invalid(String),
+}
+
+/// Something which can extract metadata from a single ebook format
+pub trait FormatReader {
+    fn read(&self, path: &Path) -> Result<BookMetadata, ReadError>;
+}
+
+/// An ebook format the program knows how to read
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Format {
+    Epub,
+    /// PDF documents, read from the Info dictionary and XMP metadata
+    Pdf,
+    /// MOBI, AZW and AZW3 books, read from their EXTH headers
+    Mobi,
+    /// Zipped comic archives, read from `ComicInfo.xml`
+    Cbz,
+    /// RAR comic archives, read from `ComicInfo.xml` using `unrar` or `bsdtar`
+    Cbr,
+    /// FictionBook documents, read from `title-info`
+    Fb2,
+}
+
+impl Format {
+    pub const ALL: &'static [Format] = &[
+        Format::Epub,
+        Format::Pdf,
+        Format::Mobi,
+        Format::Cbz,
+        Format::Cbr,
+        Format::Fb2,
+    ];
+
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Format::Epub => &["epub"],
+            Format::Pdf => &["pdf"],
+            Format::Mobi => &["mobi", "azw", "azw3"],
+            Format::Cbz => &["cbz"],
+            Format::Cbr => &["cbr"],
+            Format::Fb2 => &["fb2"],
+        }
+    }
+
+    /// Determine the format of a file from its extension
+    pub fn from_path(path: &Path) -> Option<Format> {
+        let extension = path.extension()?.to_string_lossy().to_lowercase();
+        Format::ALL
+            .iter()
+            .copied()
+            .find(|format| format.extensions().contains(&extension.as_str()))
+    }
+
+    pub fn reader(self) -> &'static dyn FormatReader {
+        match self {
+            Format::Epub => &epub::EpubReader,
+            Format::Pdf => &pdf::PdfReader,
+            Format::Mobi => &mobi::MobiReader,
+            Format::Cbz => &comic::CbzReader,
+            Format::Cbr => &comic::CbrReader,
+            Format::Fb2 => &fb2::Fb2Reader,
+        }
+    }
+}
+
+/// Add a value under `key`, ignoring values which are empty once trimmed
+fn push(metadata: &mut RawMetadata, key: &str, value: impl AsRef<str>) {
+    let value = value.as_ref().trim();
+    if !value.is_empty() {
+        metadata
+            .entry(key.to_string())
+            .or_default()
+            .push(value.to_string());
+    }
+}
@@ -0,0 +1,586 @@
+use roxmltree::{Document, Node};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, Read, Write},
+    ops::Range,
+    path::Path,
+};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use super::epub::{parse_opf, DC};
+use crate::metadata::BookMetadata;
+
+const OPF: &str = "http://www.idpf.org/2007/opf";
+const CONTAINER: &str = "META-INF/container.xml";
+const MIMETYPE: &str = "mimetype";
+
+/// Rewrite the title, creators and series in an EPUB's package document to match `book`,
+/// keeping a copy of the original at `backup`. Every other entry of the archive is copied
+/// byte-for-byte, with `mimetype` first and uncompressed as the EPUB specification requires.
+///
+/// Returns false, leaving the file untouched, when the package document already matches.
+pub fn write_metadata(path: &Path, book: &BookMetadata, backup: &Path) -> io::Result<bool> {
+    let mut archive = ZipArchive::new(File::open(path)?).map_err(invalid)?;
+    let opf_path = package_path(&read_entry(&mut archive, CONTAINER)?)?;
+    let opf = read_entry(&mut archive, &opf_path)?;
+    let Some(updated) = rewrite_opf(&opf, book)? else {
+        return Ok(false);
+    };
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let partial = path.with_file_name(format!(".{name}.partial"));
+    let mut write = || -> io::Result<()> {
+        let file = File::create(&partial)?;
+        file.set_permissions(path.metadata()?.permissions())?;
+        let mut writer = ZipWriter::new(file);
+        let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+
+        match archive.by_name(MIMETYPE) {
+            Ok(entry) if entry.compression() == CompressionMethod::Stored => {
+                writer.raw_copy_file(entry).map_err(invalid)?
+            }
+            _ => {
+                writer.start_file(MIMETYPE, stored).map_err(invalid)?;
+                writer.write_all(b"application/epub+zip")?;
+            }
+        }
+        for i in 0..archive.len() {
+            let entry = archive.by_index_raw(i).map_err(invalid)?;
+            match entry.name() {
+                MIMETYPE => {}
+                name if name == opf_path => {
+                    let options = SimpleFileOptions::default()
+                        .compression_method(entry.compression())
+                        .last_modified_time(entry.last_modified());
+                    drop(entry);
+                    writer
+                        .start_file(opf_path.as_str(), options)
+                        .map_err(invalid)?;
+                    writer.write_all(updated.as_bytes())?;
+                }
+                _ => writer.raw_copy_file(entry).map_err(invalid)?,
+            }
+        }
+        writer.finish().map_err(invalid)?.sync_all()
+    };
+    if let Err(e) = write() {
+        let _ = std::fs::remove_file(&partial);
+        return Err(e);
+    }
+
+    if let Some(parent) = backup.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::copy(path, backup)?;
+    std::fs::rename(&partial, path)?;
+    Ok(true)
+}
+
+fn invalid(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn read_entry(archive: &mut ZipArchive<File>, name: &str) -> io::Result<String> {
+    let mut contents = String::new();
+    archive
+        .by_name(name)
+        .map_err(invalid)?
+        .read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+/// The path of the package document, from the container's first rootfile
+fn package_path(container: &str) -> io::Result<String> {
+    let document = Document::parse(container.trim_start_matches('\u{feff}')).map_err(invalid)?;
+    document
+        .descendants()
+        .find(|n| n.tag_name().name() == "rootfile")
+        .and_then(|n| n.attribute("full-path"))
+        .map(str::to_string)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "The container has no rootfile"))
+}
+
+/// Rewrite the package document, or None when it already carries the book's metadata.
+///
+/// The document is edited as text, so everything which isn't rewritten keeps its exact
+/// formatting: the replaced elements are cut out and the new ones added at the end of
+/// `<metadata>`.
+fn rewrite_opf(opf: &str, book: &BookMetadata) -> io::Result<Option<String>> {
+    let text = opf.trim_start_matches('\u{feff}');
+    let current = parse_opf(text).map_err(|e| invalid(io::Error::other(e.to_string())))?;
+    let creators = |book: &BookMetadata| {
+        book.authors
+            .iter()
+            .map(|a| (a.name.clone(), a.file_as.clone()))
+            .collect::<Vec<_>>()
+    };
+    if book
+        .title()
+        .is_none_or(|title| current.title() == Some(title))
+        && creators(&current) == creators(book)
+        && current.series == book.series
+        && current.series_index == book.series_index
+    {
+        return Ok(None);
+    }
+
+    let document = Document::parse(text).map_err(invalid)?;
+    let package = document.root_element();
+    let Some(metadata) = package
+        .children()
+        .find(|n| n.is_element() && n.tag_name().name() == "metadata")
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Package document has no metadata",
+        ));
+    };
+    let epub3 = package
+        .attribute("version")
+        .is_some_and(|v| v.starts_with('3'));
+    let elements = metadata
+        .descendants()
+        .skip(1)
+        .filter(|n| n.is_element())
+        .collect::<Vec<_>>();
+
+    let mut edits: Vec<(Range<usize>, String)> = Vec::new();
+    let mut removed_ids = HashSet::new();
+    let mut remove = |node: &Node, edits: &mut Vec<(Range<usize>, String)>| {
+        if let Some(id) = node.attribute("id") {
+            removed_ids.insert(id.to_string());
+        }
+        edits.push((with_indentation(text, node.range()), String::new()));
+    };
+
+    let mut new_title = None;
+    if let Some(title) = book.title() {
+        let main = elements
+            .iter()
+            .filter(|n| is_dc(n, "title"))
+            .find(|n| current.title() == Some(normalized_text(n).as_str()))
+            .or_else(|| elements.iter().find(|n| is_dc(n, "title")));
+        match main.and_then(|n| n.first_child().filter(|c| c.is_text())) {
+            Some(text_node) => edits.push((text_node.range(), escape(title))),
+            None => {
+                if let Some(node) = main {
+                    remove(node, &mut edits);
+                }
+                new_title = Some(title);
+            }
+        }
+    }
+
+    for node in &elements {
+        let is_series_meta = node.tag_name().name() == "meta"
+            && (matches!(
+                node.attribute("name"),
+                Some("calibre:series" | "calibre:series_index")
+            ) || node.attribute("property") == Some("belongs-to-collection")
+                && is_series_collection(node, &elements));
+        if is_dc(node, "creator") || is_series_meta {
+            remove(node, &mut edits);
+        }
+    }
+    // Refinements of removed elements, such as their file-as or role
+    for node in &elements {
+        let target = node.attribute("refines").map(|t| t.trim_start_matches('#'));
+        if target.is_some_and(|t| removed_ids.contains(t)) {
+            edits.push((with_indentation(text, node.range()), String::new()));
+        }
+    }
+
+    let ids = elements
+        .iter()
+        .filter_map(|n| n.attribute("id"))
+        .filter(|id| !removed_ids.contains(*id))
+        .collect::<HashSet<_>>();
+    let new = new_elements(book, new_title, epub3, &metadata, &ids);
+
+    // New elements go on their own lines before `</metadata>` when the document is indented
+    let close = text[..metadata.range().end]
+        .rfind("</")
+        .unwrap_or(metadata.range().end);
+    let line_start = text[..close].rfind('\n').map(|i| i + 1);
+    let insertion = match line_start.filter(|&start| text[start..close].trim().is_empty()) {
+        Some(start) => {
+            let indent = elements
+                .first()
+                .map(|n| indentation(text, n.range().start))
+                .unwrap_or("  ");
+            let lines = new
+                .iter()
+                .map(|element| format!("{indent}{element}\n"))
+                .collect::<String>();
+            (start..start, lines)
+        }
+        None => (close..close, new.concat()),
+    };
+    edits.push(insertion);
+
+    edits.sort_by_key(|(range, _)| (range.start, range.end));
+    let mut output = String::with_capacity(opf.len());
+    output.push_str(&opf[..opf.len() - text.len()]);
+    let mut position = 0;
+    for (range, replacement) in edits {
+        // Ranges never overlap, except for refinements which were already cut out
+        if range.start < position {
+            continue;
+        }
+        output.push_str(&text[position..range.start]);
+        output.push_str(&replacement);
+        position = range.end;
+    }
+    output.push_str(&text[position..]);
+
+    Ok(Some(output))
+}
+
+/// The title, creator and series elements to add, in the style of the document's EPUB version
+fn new_elements(
+    book: &BookMetadata,
+    title: Option<&str>,
+    epub3: bool,
+    metadata: &Node,
+    ids: &HashSet<&str>,
+) -> Vec<String> {
+    // Prefixes are reused from the document, or declared on the element when it has none
+    let (dc, dc_declaration) = match metadata.lookup_prefix(DC) {
+        Some(prefix) if !prefix.is_empty() => (prefix, String::new()),
+        _ => ("dc", format!(" xmlns:dc=\"{DC}\"")),
+    };
+    let (opf, opf_declaration) = match metadata.lookup_prefix(OPF) {
+        Some(prefix) if !prefix.is_empty() => (prefix, String::new()),
+        _ => ("opf", format!(" xmlns:opf=\"{OPF}\"")),
+    };
+    let free_id = |base: &str| {
+        (1..)
+            .map(|n| format!("{base}{n}"))
+            .find(|id| !ids.contains(id.as_str()))
+            .expect("an unused id always exists")
+    };
+
+    let mut elements = Vec::new();
+    if let Some(title) = title {
+        elements.push(format!(
+            "<{dc}:title{dc_declaration}>{}</{dc}:title>",
+            escape(title)
+        ));
+    }
+
+    let mut used = Vec::new();
+    for author in &book.authors {
+        let name = escape(&author.name);
+        if epub3 {
+            let id = (1..)
+                .map(|n| format!("creator{n}"))
+                .find(|id| !ids.contains(id.as_str()) && !used.contains(id))
+                .expect("an unused id always exists");
+            elements.push(format!(
+                "<{dc}:creator{dc_declaration} id=\"{id}\">{name}</{dc}:creator>"
+            ));
+            if let Some(ref file_as) = author.file_as {
+                elements.push(format!(
+                    "<meta refines=\"#{id}\" property=\"file-as\">{}</meta>",
+                    escape(file_as)
+                ));
+            }
+            for role in &author.roles {
+                elements.push(format!(
+                    "<meta refines=\"#{id}\" property=\"role\" scheme=\"marc:relators\">{}</meta>",
+                    escape(role)
+                ));
+            }
+            used.push(id);
+        } else {
+            let mut attributes = String::new();
+            if let Some(role) = author.roles.first() {
+                attributes.push_str(&format!(" {opf}:role=\"{}\"", escape(role)));
+            }
+            if let Some(ref file_as) = author.file_as {
+                attributes.push_str(&format!(" {opf}:file-as=\"{}\"", escape(file_as)));
+            }
+            if !attributes.is_empty() {
+                attributes.insert_str(0, &opf_declaration);
+            }
+            elements.push(format!(
+                "<{dc}:creator{dc_declaration}{attributes}>{name}</{dc}:creator>"
+            ));
+        }
+    }
+
+    if let Some(ref series) = book.series {
+        let index = book.series_index.map(format_index);
+        if epub3 {
+            let id = free_id("series");
+            elements.push(format!(
+                "<meta property=\"belongs-to-collection\" id=\"{id}\">{}</meta>",
+                escape(series)
+            ));
+            elements.push(format!(
+                "<meta refines=\"#{id}\" property=\"collection-type\">series</meta>"
+            ));
+            if let Some(index) = index {
+                elements.push(format!(
+                    "<meta refines=\"#{id}\" property=\"group-position\">{index}</meta>"
+                ));
+            }
+        } else {
+            elements.push(format!(
+                "<meta name=\"calibre:series\" content=\"{}\"/>",
+                escape(series)
+            ));
+            if let Some(index) = index {
+                elements.push(format!(
+                    "<meta name=\"calibre:series_index\" content=\"{index}\"/>"
+                ));
+            }
+        }
+    }
+
+    elements
+}
+
+fn is_dc(node: &Node, name: &str) -> bool {
+    node.tag_name().namespace() == Some(DC) && node.tag_name().name() == name
+}
+
+/// Whether a collection is the one the reader takes the series from: typed as a series, or
+/// without a type
+fn is_series_collection(node: &Node, elements: &[Node]) -> bool {
+    let Some(id) = node.attribute("id") else {
+        return true;
+    };
+    let collection_type = elements.iter().find(|n| {
+        n.attribute("refines").map(|t| t.trim_start_matches('#')) == Some(id)
+            && n.attribute("property") == Some("collection-type")
+    });
+    collection_type.is_none_or(|n| normalized_text(n) == "series")
+}
+
+fn normalized_text(node: &Node) -> String {
+    node.text()
+        .unwrap_or_default()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Extend an element's range over the indentation and line break in front of it, so that
+/// removing it doesn't leave a blank line behind
+fn with_indentation(text: &str, range: Range<usize>) -> Range<usize> {
+    let indent = indentation(text, range.start);
+    let start = range.start - indent.len();
+    match text[..start].strip_suffix('\n') {
+        Some(before) if !indent.is_empty() || text[..start].ends_with('\n') => {
+            (before.strip_suffix('\r').unwrap_or(before).len())..range.end
+        }
+        _ => start..range.end,
+    }
+}
+
+/// The whitespace between the start of the line and `position`, if there is nothing else
+fn indentation(text: &str, position: usize) -> &str {
+    let line_start = text[..position].rfind('\n').map_or(0, |i| i + 1);
+    let before = &text[line_start..position];
+    match before.trim().is_empty() {
+        true => before,
+        false => "",
+    }
+}
+
+fn format_index(index: f64) -> String {
+    match index.fract() == 0.0 {
+        true => format!("{index:.0}"),
+        false => index.to_string(),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::Contributor;
+
+    const EPUB2: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+    <dc:title>Dune</dc:title>
+    <dc:creator opf:role="aut" opf:file-as="Herbert, F.">F. Herbert</dc:creator>
+    <dc:language>en</dc:language>
+    <dc:identifier id="uid" opf:scheme="ISBN">9780441013593</dc:identifier>
+    <meta name="calibre:series" content="Dune"/>
+    <meta name="calibre:series_index" content="1"/>
+  </metadata>
+  <manifest>
+    <item id="c1" href="c1.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine><itemref idref="c1"/></spine>
+</package>
+"#;
+
+    const EPUB3: &str = r##"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">urn:uuid:1234</dc:identifier>
+    <dc:title id="t1">The Hobbit</dc:title>
+    <meta refines="#t1" property="title-type">main</meta>
+    <dc:creator id="c1">JRR Tolkien</dc:creator>
+    <meta refines="#c1" property="file-as">Tolkien</meta>
+    <meta refines="#c1" property="role" scheme="marc:relators">aut</meta>
+    <meta property="belongs-to-collection" id="s1">Middle Earth</meta>
+    <meta refines="#s1" property="group-position">2</meta>
+    <dc:language>en</dc:language>
+    <meta property="dcterms:modified">2020-01-01T00:00:00Z</meta>
+  </metadata>
+  <manifest>
+    <item id="c1" href="c1.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine><itemref idref="c1"/></spine>
+</package>
+"##;
+
+    fn author(name: &str, file_as: &str) -> Contributor {
+        Contributor {
+            name: name.to_string(),
+            file_as: Some(file_as.to_string()),
+            roles: vec!["aut".to_string()],
+        }
+    }
+
+    fn book(title: &str, authors: Vec<Contributor>, series: &str, index: f64) -> BookMetadata {
+        BookMetadata {
+            titles: vec![title.to_string()],
+            authors,
+            series: Some(series.to_string()),
+            series_index: Some(index),
+            ..Default::default()
+        }
+    }
+
+    /// The lines of `original` which don't contain any of `removed`, as they should appear in
+    /// the rewritten document
+    fn kept_lines<'a>(original: &'a str, removed: &[&str]) -> Vec<&'a str> {
+        original
+            .lines()
+            .filter(|line| !removed.iter().any(|r| line.contains(r)))
+            .collect()
+    }
+
+    #[test]
+    fn rewrites_epub2_creators_with_file_as() {
+        let book = book(
+            "Dune",
+            vec![author("Frank Herbert", "Herbert, Frank")],
+            "Dune Chronicles",
+            1.0,
+        );
+        let rewritten = rewrite_opf(EPUB2, &book).unwrap().unwrap();
+
+        assert!(rewritten.contains(
+            r#"<dc:creator opf:role="aut" opf:file-as="Herbert, Frank">Frank Herbert</dc:creator>"#
+        ));
+        assert!(rewritten.contains(r#"<meta name="calibre:series" content="Dune Chronicles"/>"#));
+        assert!(rewritten.contains(r#"<meta name="calibre:series_index" content="1"/>"#));
+        assert!(!rewritten.contains("F. Herbert"));
+
+        let read = parse_opf(&rewritten).unwrap();
+        assert_eq!(read.title(), Some("Dune"));
+        assert_eq!(read.authors, book.authors);
+        assert_eq!(read.series, book.series);
+        assert_eq!(read.series_index, book.series_index);
+        assert_eq!(read.identifiers.len(), 1);
+
+        // Reading the rewritten document back gives the same metadata, so nothing is left to do
+        assert!(rewrite_opf(&rewritten, &book).unwrap().is_none());
+    }
+
+    #[test]
+    fn rewrites_epub3_creators_with_refinements() {
+        let book = book(
+            "The Hobbit, or There and Back Again",
+            vec![
+                author("J. R. R. Tolkien", "Tolkien, J. R. R."),
+                author("Christopher Tolkien", "Tolkien, Christopher"),
+            ],
+            "Middle-earth",
+            2.5,
+        );
+        let rewritten = rewrite_opf(EPUB3, &book).unwrap().unwrap();
+
+        // The old creator, its refinements and the old collection are gone, ids included
+        assert!(!rewritten.contains("JRR Tolkien"));
+        assert!(!rewritten.contains(r##"refines="#c1""##));
+        assert!(!rewritten.contains(r##"refines="#s1""##));
+        assert!(!rewritten.contains("Middle Earth"));
+        // The title keeps its element, id and refinement
+        assert!(rewritten
+            .contains(r#"<dc:title id="t1">The Hobbit, or There and Back Again</dc:title>"#));
+        assert!(rewritten.contains(r##"<meta refines="#t1" property="title-type">main</meta>"##));
+
+        let read = parse_opf(&rewritten).unwrap();
+        assert_eq!(read.title(), book.title());
+        assert_eq!(read.authors, book.authors);
+        assert_eq!(read.series, book.series);
+        assert_eq!(read.series_index, Some(2.5));
+        assert!(rewrite_opf(&rewritten, &book).unwrap().is_none());
+    }
+
+    #[test]
+    fn preserves_formatting_outside_rewritten_elements() {
+        let book = book(
+            "The Hobbit",
+            vec![author("J. R. R. Tolkien", "Tolkien, J. R. R.")],
+            "Middle Earth",
+            2.0,
+        );
+        let rewritten = rewrite_opf(EPUB3, &book).unwrap().unwrap();
+
+        // Every untouched line is kept as is and in order, and removed elements leave no blank
+        // lines behind
+        let removed = ["dc:creator", "refines=\"#c1\"", "refines=\"#s1\"", "s1"];
+        let added = ["creator1", "series1"];
+        assert_eq!(kept_lines(&rewritten, &added), kept_lines(EPUB3, &removed));
+        assert!(!rewritten.lines().any(|line| line.trim().is_empty()));
+
+        // New elements are indented like their siblings, before `</metadata>`
+        let metadata_end = rewritten.find("  </metadata>").unwrap();
+        let creator = rewritten
+            .find("\n    <dc:creator id=\"creator1\">J. R. R. Tolkien</dc:creator>\n")
+            .unwrap();
+        assert!(creator < metadata_end);
+    }
+
+    #[test]
+    fn escapes_text_and_reuses_bound_prefixes() {
+        let opf = EPUB2
+            .replace("xmlns:dc=", "xmlns:purl=")
+            .replace("dc:", "purl:");
+        let book = book(
+            "Q & A <2>",
+            vec![author("Tom \"T\" Jones", "Jones, Tom")],
+            "S",
+            3.0,
+        );
+        let rewritten = rewrite_opf(&opf, &book).unwrap().unwrap();
+
+        assert!(rewritten.contains("<purl:title>Q &amp; A &lt;2&gt;</purl:title>"));
+        assert!(rewritten.contains(
+            r#"<purl:creator opf:role="aut" opf:file-as="Jones, Tom">Tom &quot;T&quot; Jones</purl:creator>"#
+        ));
+        assert_eq!(parse_opf(&rewritten).unwrap().title(), Some("Q & A <2>"));
+    }
+
+    #[test]
+    fn leaves_matching_documents_untouched() {
+        let current = parse_opf(EPUB2).unwrap();
+        assert!(rewrite_opf(EPUB2, &current).unwrap().is_none());
+    }
+}
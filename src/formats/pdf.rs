@@ -0,0 +1,144 @@
+use lopdf::{Dictionary, Document};
+use std::path::Path;
+
+use super::{push, FormatReader, RawMetadata, ReadError};
+use crate::metadata::BookMetadata;
+
+const DC: &str = "http://purl.org/dc/elements/1.1/";
+const XMP: &str = "http://ns.adobe.com/xap/1.0/";
+const RDF: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+
+pub struct PdfReader;
+
+impl FormatReader for PdfReader {
+    fn read(&self, path: &Path) -> Result<BookMetadata, ReadError> {
+        let document = Document::load(path).map_err(|e| ReadError::Invalid(e.to_string()))?;
+
+        // XMP is preferred as it is usually more complete and always Unicode, with the Info
+        // dictionary filling in anything it lacks
+        let mut metadata = read_xmp(&document).unwrap_or_default();
+        if let Some(info) = info_dictionary(&document) {
+            for (key, values) in read_info(&document, info) {
+                metadata.entry(key).or_insert(values);
+            }
+        }
+
+        Ok(BookMetadata::from_raw(metadata))
+    }
+}
+
+fn info_dictionary(document: &Document) -> Option<&Dictionary> {
+    let info = document.trailer.get(b"Info").ok()?;
+    document.dereference(info).ok()?.1.as_dict().ok()
+}
+
+fn read_info(document: &Document, info: &Dictionary) -> RawMetadata {
+    let text = |key: &[u8]| {
+        let object = info.get(key).ok()?;
+        let object = document.dereference(object).ok()?.1;
+        lopdf::decode_text_string(object).ok()
+    };
+
+    let mut metadata = RawMetadata::new();
+    if let Some(title) = text(b"Title") {
+        push(&mut metadata, "title", title);
+    }
+    if let Some(author) = text(b"Author") {
+        for author in author.split(';') {
+            push(&mut metadata, "creator", author);
+        }
+    }
+    if let Some(subject) = text(b"Subject") {
+        push(&mut metadata, "description", subject);
+    }
+    if let Some(keywords) = text(b"Keywords") {
+        for keyword in keywords.split([',', ';']) {
+            push(&mut metadata, "subject", keyword);
+        }
+    }
+    if let Some(date) = text(b"CreationDate").and_then(|date| parse_pdf_date(&date)) {
+        push(&mut metadata, "date", date);
+    }
+    metadata
+}
+
+/// Convert a PDF date such as `D:19650801120000Z` into `1965-08-01`
+fn parse_pdf_date(date: &str) -> Option<String> {
+    let digits = date
+        .trim_start_matches("D:")
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect::<String>();
+    match digits.len() {
+        0..4 => None,
+        4..6 => Some(digits[..4].to_string()),
+        6..8 => Some(format!("{}-{}", &digits[..4], &digits[4..6])),
+        _ => Some(format!(
+            "{}-{}-{}",
+            &digits[..4],
+            &digits[4..6],
+            &digits[6..8]
+        )),
+    }
+}
+
+fn read_xmp(document: &Document) -> Option<RawMetadata> {
+    let catalog = document.catalog().ok()?;
+    let stream = document
+        .dereference(catalog.get(b"Metadata").ok()?)
+        .ok()?
+        .1
+        .as_stream()
+        .ok()?;
+    let content = stream
+        .decompressed_content()
+        .unwrap_or_else(|_| stream.content.clone());
+    let xml = String::from_utf8_lossy(&content);
+    let xml = xml.trim_start_matches('\u{feff}');
+    let xmp = roxmltree::Document::parse(xml).ok()?;
+
+    let mut metadata = RawMetadata::new();
+    for node in xmp.descendants().filter(|n| n.is_element()) {
+        let namespace = node.tag_name().namespace();
+        let key = match (namespace, node.tag_name().name()) {
+            (Some(DC), "title") => "title",
+            (Some(DC), "creator") => "creator",
+            (Some(DC), "contributor") => "contributor",
+            (Some(DC), "description") => "description",
+            (Some(DC), "subject") => "subject",
+            (Some(DC), "publisher") => "publisher",
+            (Some(DC), "language") => "language",
+            (Some(DC), "identifier") => "identifier",
+            (Some(DC), "date") => "date",
+            (Some(XMP), "CreateDate") if !metadata.contains_key("date") => "date",
+            _ => continue,
+        };
+
+        // Values are either plain text or wrapped in an `rdf:Alt`, `rdf:Bag` or `rdf:Seq`
+        let items = node
+            .descendants()
+            .filter(|n| n.tag_name().namespace() == Some(RDF) && n.tag_name().name() == "li")
+            .filter_map(|n| n.text())
+            .collect::<Vec<_>>();
+        if items.is_empty() {
+            if let Some(text) = node.text() {
+                push(&mut metadata, key, text);
+            }
+        } else if key == "title" || key == "description" {
+            // Alternatives are translations of the same value, only keep the default one
+            push(&mut metadata, key, items[0]);
+        } else {
+            for item in items {
+                push(&mut metadata, key, item);
+            }
+        }
+    }
+
+    if let Some(dates) = metadata.get_mut("date") {
+        for date in dates.iter_mut() {
+            *date = date.chars().take(10).collect();
+        }
+    }
+
+    Some(metadata).filter(|m| !m.is_empty())
+}
@@ -0,0 +1,17 @@
+use std::{fs::File, io, path::Path};
+
+/// Compute the BLAKE3 hash of a file, streaming its contents rather than reading it all at once
+pub fn hash_file(path: &Path) -> io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    let mut file = File::open(path)?;
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// Whether two files have identical contents
+pub fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+    Ok(hash_file(a)? == hash_file(b)?)
+}
@@ -0,0 +1,246 @@
+use rusqlite::{params, Connection, OpenFlags};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::{journal::JOURNAL_DIR, metadata::BookMetadata, place::PlaceStrategy};
+
+/// The index's file name, in the same directory as the journals
+const INDEX_FILE: &str = "index.sqlite";
+/// Bumped whenever the schema or the cached metadata changes shape, discarding older indexes
+const SCHEMA_VERSION: i32 = 1;
+
+/// The size and modification time of a file, which decide whether its index entry is current
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    pub size: u64,
+    /// Nanoseconds since the Unix epoch
+    pub modified: i64,
+}
+
+impl FileStamp {
+    pub fn of(path: &Path) -> io::Result<FileStamp> {
+        let metadata = path.metadata()?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as i64)
+            .unwrap_or_default();
+        Ok(FileStamp {
+            size: metadata.len(),
+            modified,
+        })
+    }
+}
+
+/// What the index remembers about a single file
+#[derive(Debug, Clone)]
+pub struct IndexEntry {
+    pub stamp: FileStamp,
+    pub hash: blake3::Hash,
+    /// The metadata as read from the file, before author normalization
+    pub metadata: BookMetadata,
+}
+
+/// The contents of the index, loaded up front so that scan workers can share it
+#[derive(Debug, Default)]
+pub struct IndexSnapshot {
+    entries: HashMap<PathBuf, IndexEntry>,
+    by_hash: HashMap<blake3::Hash, PathBuf>,
+}
+
+impl IndexSnapshot {
+    /// The entry for a file, if it has not changed since it was indexed
+    pub fn get(&self, path: &Path, stamp: FileStamp) -> Option<&IndexEntry> {
+        self.entries.get(path).filter(|entry| entry.stamp == stamp)
+    }
+
+    /// Any entry for a file with the given contents, wherever it was indexed
+    pub fn get_by_hash(&self, hash: &blake3::Hash) -> Option<&IndexEntry> {
+        self.entries.get(self.by_hash.get(hash)?)
+    }
+}
+
+/// A persistent index of every ebook seen in the library, so that unchanged files are not
+/// parsed again on the next run
+pub struct LibraryIndex {
+    connection: Connection,
+}
+
+impl LibraryIndex {
+    /// Open the index in the output directory, creating it if needed
+    pub fn open(output: &Path) -> eyre::Result<LibraryIndex> {
+        let dir = output.join(JOURNAL_DIR);
+        std::fs::create_dir_all(&dir)?;
+        let connection = Connection::open(dir.join(INDEX_FILE))?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+
+        let version: i32 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version != SCHEMA_VERSION {
+            connection.execute_batch("DROP TABLE IF EXISTS books")?;
+            connection.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        }
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS books (
+                path TEXT PRIMARY KEY,
+                size INTEGER NOT NULL,
+                modified INTEGER NOT NULL,
+                hash TEXT NOT NULL,
+                metadata TEXT NOT NULL,
+                destination TEXT
+            );
+            CREATE INDEX IF NOT EXISTS books_hash ON books (hash);",
+        )?;
+
+        Ok(LibraryIndex { connection })
+    }
+
+    /// Open an existing index without being able to change it, as dry runs must not write to
+    /// the output directory
+    pub fn open_read_only(output: &Path) -> eyre::Result<Option<LibraryIndex>> {
+        let path = output.join(JOURNAL_DIR).join(INDEX_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        // Even read-only connections create the shared-memory and WAL files of a database in
+        // WAL mode, unless it is opened as immutable
+        let connection = Connection::open_with_flags(
+            immutable_uri(&path),
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI,
+        )?;
+        let version: i32 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        Ok((version == SCHEMA_VERSION).then_some(LibraryIndex { connection }))
+    }
+
+    /// Forget every indexed file, forcing a full rescan
+    pub fn clear(&self) -> rusqlite::Result<()> {
+        self.connection.execute("DELETE FROM books", [])?;
+        Ok(())
+    }
+
+    /// Load every entry. Entries which can no longer be read are left out and replaced on the
+    /// next scan.
+    pub fn load(&self) -> rusqlite::Result<IndexSnapshot> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT path, size, modified, hash, metadata FROM books")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+            ))
+        })?;
+
+        let mut snapshot = IndexSnapshot::default();
+        for row in rows {
+            let (path, size, modified, hash, metadata) = row?;
+            let (Ok(hash), Ok(metadata)) = (
+                blake3::Hash::from_hex(&hash),
+                serde_json::from_str::<BookMetadata>(&metadata),
+            ) else {
+                continue;
+            };
+            let path = PathBuf::from(path);
+            snapshot.by_hash.insert(hash, path.clone());
+            snapshot.entries.insert(
+                path,
+                IndexEntry {
+                    stamp: FileStamp {
+                        size: size as u64,
+                        modified,
+                    },
+                    hash,
+                    metadata,
+                },
+            );
+        }
+        Ok(snapshot)
+    }
+
+    /// Add or replace the entries for files which were parsed during this run
+    pub fn store<'a>(
+        &mut self,
+        entries: impl IntoIterator<Item = (&'a Path, &'a IndexEntry)>,
+    ) -> eyre::Result<()> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement = transaction.prepare(
+                "INSERT OR REPLACE INTO books (path, size, modified, hash, metadata)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for (path, entry) in entries {
+                statement.execute(params![
+                    path.to_string_lossy(),
+                    entry.stamp.size as i64,
+                    entry.stamp.modified,
+                    entry.hash.to_hex().as_str(),
+                    serde_json::to_string(&entry.metadata)?,
+                ])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Remember where a file was placed. A moved file's entry follows it to its new path and a
+    /// copy or link gets an entry of its own, so neither is parsed again on the next run.
+    pub fn record_placement(
+        &self,
+        source: &Path,
+        destination: &Path,
+        strategy: &PlaceStrategy,
+    ) -> eyre::Result<()> {
+        let stamp = FileStamp::of(destination)?;
+        let (source, destination) = (source.to_string_lossy(), destination.to_string_lossy());
+
+        self.connection
+            .execute("DELETE FROM books WHERE path = ?1", [&destination])?;
+        match strategy {
+            PlaceStrategy::Move => {
+                self.connection.execute(
+                    "UPDATE books SET path = ?2, size = ?3, modified = ?4, destination = ?2
+                     WHERE path = ?1",
+                    params![source, destination, stamp.size as i64, stamp.modified],
+                )?;
+            }
+            _ => {
+                self.connection.execute(
+                    "INSERT INTO books (path, size, modified, hash, metadata, destination)
+                     SELECT ?2, ?3, ?4, hash, metadata, ?2 FROM books WHERE path = ?1",
+                    params![source, destination, stamp.size as i64, stamp.modified],
+                )?;
+                self.connection.execute(
+                    "UPDATE books SET destination = ?2 WHERE path = ?1",
+                    params![source, destination],
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A `file:` URI opening the database at `path` as immutable, with every byte of the path which
+/// isn't unreserved percent-encoded
+fn immutable_uri(path: &Path) -> String {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut encoded = String::new();
+    for byte in path.as_os_str().as_encoded_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(*byte as char)
+            }
+            b'\\' if cfg!(windows) => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    // Windows paths start with a drive letter rather than a slash
+    let slash = if encoded.starts_with('/') { "" } else { "/" };
+    format!("file:{slash}{encoded}?immutable=1")
+}
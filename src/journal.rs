@@ -0,0 +1,381 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    hash,
+    place::{self, PlaceStrategy},
+};
+
+/// The directory, relative to the output directory, where journals are stored
+pub const JOURNAL_DIR: &str = ".ebook-sorter";
+
+/// A single change made to the filesystem during a run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum JournalEntry {
+    /// A directory which did not exist before the run was created
+    Mkdir { path: PathBuf },
+    /// An ebook was placed at `destination`
+    Place {
+        source: PathBuf,
+        destination: PathBuf,
+        strategy: PlaceStrategy,
+        /// The BLAKE3 hash of the file at `destination` right after it was placed
+        hash: String,
+        /// Where the existing file at `destination` was kept, if the ebook replaced one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        backup: Option<PathBuf>,
+        /// Where the symbolic link at `destination` points, for the symlink strategies
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target: Option<PathBuf>,
+    },
+    /// The metadata inside a placed ebook was rewritten, after keeping the original at `backup`
+    WriteMetadata {
+        path: PathBuf,
+        backup: PathBuf,
+        /// The BLAKE3 hash of the file at `path` right after it was rewritten
+        hash: String,
+    },
+}
+
+/// An append-only record of every change made during a run, written as JSON lines so that
+/// a run which is interrupted still leaves a usable journal behind. The file is only created
+/// with the first entry, so a run which changes nothing leaves no journal for `undo` to pick up.
+pub struct Journal {
+    path: PathBuf,
+    writer: Option<BufWriter<File>>,
+    backups: usize,
+}
+
+impl Journal {
+    /// Create a new, timestamped journal in the output directory
+    pub fn create(output: &Path) -> io::Result<Journal> {
+        let dir = output.join(JOURNAL_DIR);
+        std::fs::create_dir_all(&dir)?;
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        Ok(Journal {
+            path: dir.join(format!("journal-{timestamp}.jsonl")),
+            writer: None,
+            backups: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether nothing has been recorded yet, in which case the journal file doesn't exist
+    pub fn is_empty(&self) -> bool {
+        self.writer.is_none()
+    }
+
+    /// A new path for a backup of `file`, in a directory named after the journal
+    pub fn backup_path(&mut self, file: &Path) -> PathBuf {
+        self.backups += 1;
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        self.path
+            .with_extension("backup")
+            .join(format!("{}-{name}", self.backups))
+    }
+
+    /// Append an entry, with its paths made absolute so that the journal can be undone from
+    /// any working directory
+    pub fn record(&mut self, entry: &JournalEntry) -> io::Result<()> {
+        let entry = match entry.clone() {
+            JournalEntry::Mkdir { path } => JournalEntry::Mkdir {
+                path: absolute(&path)?,
+            },
+            JournalEntry::Place {
+                source,
+                destination,
+                strategy,
+                hash,
+                backup,
+                target,
+            } => JournalEntry::Place {
+                source: absolute(&source)?,
+                destination: absolute(&destination)?,
+                strategy,
+                hash,
+                backup: backup.as_deref().map(absolute).transpose()?,
+                target,
+            },
+            JournalEntry::WriteMetadata { path, backup, hash } => JournalEntry::WriteMetadata {
+                path: absolute(&path)?,
+                backup: absolute(&backup)?,
+                hash,
+            },
+        };
+        let writer = match self.writer {
+            Some(ref mut writer) => writer,
+            None => {
+                let file = OpenOptions::new()
+                    .create_new(true)
+                    .append(true)
+                    .open(&self.path)?;
+                self.writer.insert(BufWriter::new(file))
+            }
+        };
+        serde_json::to_writer(&mut *writer, &entry)?;
+        writer.write_all(b"\n")?;
+        writer.flush()
+    }
+
+    /// Record a placement, hashing the file which now lives at `destination`. `backup` is where
+    /// the file it replaced was kept, if it replaced one.
+    pub fn record_place(
+        &mut self,
+        source: &Path,
+        destination: &Path,
+        strategy: &PlaceStrategy,
+        backup: Option<&Path>,
+    ) -> io::Result<()> {
+        let hash = hash::hash_file(destination)?;
+        let target = match strategy {
+            PlaceStrategy::Symlink | PlaceStrategy::RelativeSymlink => {
+                Some(std::fs::read_link(destination)?)
+            }
+            _ => None,
+        };
+        self.record(&JournalEntry::Place {
+            source: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+            strategy: strategy.clone(),
+            hash: hash.to_hex().to_string(),
+            backup: backup.map(Path::to_path_buf),
+            target,
+        })
+    }
+}
+
+/// The canonical form of a path, without resolving the last component, which may be a symbolic
+/// link placed by the run or a file which was moved away
+fn absolute(path: &Path) -> io::Result<PathBuf> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => {
+            match parent.canonicalize() {
+                Ok(parent) => Ok(parent.join(name)),
+                Err(_) => std::path::absolute(path),
+            }
+        }
+        _ => std::path::absolute(path),
+    }
+}
+
+/// Find the most recent journal in the output directory which has not been undone yet
+pub fn latest(output: &Path) -> io::Result<Option<PathBuf>> {
+    let dir = output.join(JOURNAL_DIR);
+    if !dir.exists() {
+        return Ok(None);
+    }
+
+    let mut journals = std::fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension() == Some("jsonl".as_ref())
+                && path
+                    .file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with("journal-"))
+        })
+        .collect::<Vec<_>>();
+    journals.sort_by_key(|path| {
+        path.file_stem()
+            .and_then(|stem| {
+                stem.to_string_lossy()
+                    .strip_prefix("journal-")?
+                    .parse::<u128>()
+                    .ok()
+            })
+            .unwrap_or_default()
+    });
+
+    Ok(journals.pop())
+}
+
+/// The result of reverting a single journal entry
+#[derive(Debug)]
+pub enum UndoOutcome {
+    /// A moved ebook was returned to its original path
+    Restored,
+    /// A copied ebook was deleted from its destination
+    RemovedCopy,
+    /// A hard or symbolic link to an ebook was deleted from its destination
+    RemovedLink,
+    /// A directory created by the run was removed
+    RemovedDirectory,
+    /// An ebook whose metadata was rewritten was replaced with its backup
+    RestoredMetadata,
+    /// A directory created by the run still contains other files, so it was left in place
+    DirectoryNotEmpty,
+    /// The ebook was reverted and the file it replaced during the run was put back
+    RestoredReplaced,
+    /// The file at the destination no longer exists
+    Missing,
+    /// The file at the destination has changed since it was placed
+    HashChanged,
+    /// Something already exists at the original path
+    SourceOccupied,
+    Failed(io::Error),
+}
+
+impl UndoOutcome {
+    pub fn is_success(&self) -> bool {
+        matches!(
+            self,
+            UndoOutcome::Restored
+                | UndoOutcome::RemovedCopy
+                | UndoOutcome::RemovedLink
+                | UndoOutcome::RemovedDirectory
+                | UndoOutcome::RestoredMetadata
+                | UndoOutcome::RestoredReplaced
+        )
+    }
+}
+
+impl fmt::Display for UndoOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UndoOutcome::Restored => write!(f, "Restored"),
+            UndoOutcome::RemovedCopy => write!(f, "Removed copy"),
+            UndoOutcome::RemovedLink => write!(f, "Removed link"),
+            UndoOutcome::RemovedDirectory => write!(f, "Removed directory"),
+            UndoOutcome::RestoredMetadata => write!(f, "Restored original metadata"),
+            UndoOutcome::DirectoryNotEmpty => write!(f, "Kept directory, not empty"),
+            UndoOutcome::RestoredReplaced => {
+                write!(f, "Reverted, and restored the file it replaced")
+            }
+            UndoOutcome::Missing => write!(f, "Refused, file no longer exists"),
+            UndoOutcome::HashChanged => write!(f, "Refused, file changed since the run"),
+            UndoOutcome::SourceOccupied => write!(f, "Refused, original path is occupied"),
+            UndoOutcome::Failed(e) => write!(f, "Failed: {e}"),
+        }
+    }
+}
+
+/// Read every entry of a journal, in the order they were recorded
+pub fn read(path: &Path) -> eyre::Result<Vec<JournalEntry>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .map_err(|e| eyre::eyre!("Invalid journal entry on line {}: {e}", number + 1))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Revert a single journal entry, refusing to touch files which changed since the run
+pub fn revert(entry: &JournalEntry) -> UndoOutcome {
+    match entry {
+        JournalEntry::Mkdir { path } => match std::fs::remove_dir(path) {
+            Ok(()) => UndoOutcome::RemovedDirectory,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => UndoOutcome::Missing,
+            Err(_) if path.read_dir().is_ok_and(|mut d| d.next().is_some()) => {
+                UndoOutcome::DirectoryNotEmpty
+            }
+            Err(e) => UndoOutcome::Failed(e),
+        },
+        JournalEntry::Place {
+            source,
+            destination,
+            strategy,
+            hash,
+            backup,
+            target,
+        } => {
+            // Checked without following symbolic links, whose target may be gone
+            if destination.symlink_metadata().is_err() {
+                return UndoOutcome::Missing;
+            }
+            // A symbolic link is still the one placed if it points to the same target, even
+            // when the ebook it leads to has changed or is gone
+            let unchanged = match target {
+                Some(target) => std::fs::read_link(destination).map(|current| current == *target),
+                None => {
+                    hash::hash_file(destination).map(|current| current.to_hex().as_str() == hash)
+                }
+            };
+            match unchanged {
+                Ok(true) => {}
+                Ok(false) => return UndoOutcome::HashChanged,
+                Err(e) => return UndoOutcome::Failed(e),
+            }
+
+            let result = match strategy {
+                PlaceStrategy::Move => {
+                    if source.exists() {
+                        return UndoOutcome::SourceOccupied;
+                    }
+                    source
+                        .parent()
+                        .map_or(Ok(()), std::fs::create_dir_all)
+                        .and_then(|_| place::move_file(destination, source))
+                }
+                _ => std::fs::remove_file(destination),
+            };
+
+            if let Err(e) = result {
+                return UndoOutcome::Failed(e);
+            }
+            match (backup, strategy) {
+                (Some(backup), _) => match place::move_file(backup, destination) {
+                    Ok(()) => {
+                        remove_backup_dir(backup);
+                        UndoOutcome::RestoredReplaced
+                    }
+                    Err(e) => UndoOutcome::Failed(e),
+                },
+                (None, PlaceStrategy::Move) => UndoOutcome::Restored,
+                (None, PlaceStrategy::Copy | PlaceStrategy::Reflink) => UndoOutcome::RemovedCopy,
+                (None, _) => UndoOutcome::RemovedLink,
+            }
+        }
+        JournalEntry::WriteMetadata { path, backup, hash } => {
+            if !path.exists() || !backup.exists() {
+                return UndoOutcome::Missing;
+            }
+            match hash::hash_file(path) {
+                Ok(current) if current.to_hex().as_str() == hash => {}
+                Ok(_) => return UndoOutcome::HashChanged,
+                Err(e) => return UndoOutcome::Failed(e),
+            }
+
+            match place::move_file(backup, path) {
+                Ok(()) => {
+                    remove_backup_dir(backup);
+                    UndoOutcome::RestoredMetadata
+                }
+                Err(e) => UndoOutcome::Failed(e),
+            }
+        }
+    }
+}
+
+/// Remove the directory a restored backup was kept in, once it holds no other backups
+fn remove_backup_dir(backup: &Path) {
+    let _ = backup.parent().map(std::fs::remove_dir);
+}
+
+/// Mark a journal as undone so that it is not picked up again by a later `undo`
+pub fn mark_undone(path: &Path) -> io::Result<PathBuf> {
+    let mut undone = path.as_os_str().to_os_string();
+    undone.push(".undone");
+    let undone = PathBuf::from(undone);
+    std::fs::rename(path, &undone)?;
+    Ok(undone)
+}
@@ -1,23 +1,70 @@
 use comfy_table::{modifiers::UTF8_ROUND_CORNERS, presets::UTF8_FULL, ContentArrangement, Table};
-use epub::doc::EpubDoc;
 use eyre::Result;
-use indicatif::{ProgressBar, ProgressStyle};
-use std::{fs::File, io::BufReader, path::PathBuf};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, Instant},
+};
 
-use clap::{Parser, ValueEnum};
-use walkdir::{DirEntry, WalkDir};
+use clap::{Parser, Subcommand};
+use walkdir::WalkDir;
 
-#[derive(ValueEnum, Clone, Default, Debug)]
-enum PlaceStrategy {
-    Copy,
-    #[default]
-    Move,
-}
+mod audit;
+mod authors;
+mod config;
+mod duplicates;
+mod error;
+mod filename;
+mod formats;
+mod hash;
+mod index;
+mod journal;
+mod metadata;
+mod place;
+mod plan;
+mod report;
+mod sanitize;
+mod template;
+mod watch;
+
+use authors::{AuthorNormalizer, AuthorPolicy, MultiAuthor, NameForm};
+use duplicates::{DuplicateAction, DuplicateFinder, DuplicatePolicy};
+use error::{EbookSortError, Stage, EXIT_PARTIAL};
+use filename::FilenamePattern;
+use formats::{Format, ReadError};
+use index::{FileStamp, IndexEntry, IndexSnapshot, LibraryIndex};
+use journal::{Journal, JournalEntry};
+use metadata::BookMetadata;
+use place::{ConflictPolicy, Outcome, PlaceStrategy};
+use plan::{Placement, Plan, ScanInfo};
+use report::{Action, Report, ReportFormat};
+use sanitize::SanitizeProfile;
+use template::{RenderContext, Template};
 
 /// A program to organize your ebooks by extracting metadata from them.
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    after_help = "Exits with 0 when every ebook was sorted, 1 on a fatal error, 2 on invalid \
+                  arguments and 3 when some ebooks could not be read or placed."
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// The config file to read settings from. If not provided,
+    /// `~/.config/ebook-sorter/config.toml` is used when it exists. Flags given on the command
+    /// line always override the config file.
+    #[clap(long)]
+    config: Option<PathBuf>,
+    /// The profile in the config file to use, on top of its defaults.
+    #[clap(long)]
+    profile: Option<String>,
     /// The root directory where the program will search for ebooks. If not provided,
     /// the program will search for ebooks in the current directory.
     #[clap(short, long)]
@@ -30,129 +77,817 @@ struct Args {
     /// move the ebooks.
     #[clap(short, long, default_value = "move")]
     strategy: PlaceStrategy,
+    /// What to do when a file already exists at an ebook's destination. If not provided,
+    /// the program will leave both files untouched.
+    #[clap(long, value_enum, default_value = "skip")]
+    on_conflict: ConflictPolicy,
+    /// The template used to build each ebook's destination, relative to the output directory.
+    /// Placeholders such as `{author}`, `{title}`, `{series}`, `{series_index:02}`, `{year}` and
+    /// `{ext}` are replaced with the ebook's metadata. Fallbacks can be chained with `|`, e.g.
+    /// `{series|"Standalone"}`, followed by the `upper`, `lower`, `titlecase`, `trim`, `initial`
+    /// and `trunc(N)` filters. Text wrapped in `<...>` is left out unless every placeholder in it
+    /// has a value, and directories which render empty are skipped, so books without a series
+    /// stay at the author level by default.
+    #[clap(short, long, default_value = Template::DEFAULT)]
+    template: Template,
+    /// Whether `{author}` uses display names such as `J. R. R. Tolkien` or sort names such as
+    /// `Tolkien, J. R. R.`. Sort names come from the book when it provides them.
+    #[clap(long, value_enum, default_value = "display")]
+    author_form: NameForm,
+    /// How books with more than one author are credited: only the `first` author, `all` of
+    /// them, only those with the `aut` role (`primary-role-only`), or placed in an
+    /// `Anthologies` folder when there are too many (`anthology`).
+    #[clap(long, value_enum, default_value = "all")]
+    multi_author: MultiAuthor,
+    /// The number of authors credited before the rest are replaced with `et al.`
+    #[clap(long, default_value_t = 3)]
+    max_authors: usize,
+    /// A TOML file mapping canonical author names to their variants, so that every variant is
+    /// sorted under the same name, e.g. `[aliases]` then `"J. R. R. Tolkien" = ["JRR Tolkien"]`.
+    #[clap(long)]
+    aliases: Option<PathBuf>,
+    /// The filesystem rules every generated file and directory name must satisfy. Reserved
+    /// characters are replaced with `_`, control characters are removed and names are
+    /// normalized to Unicode NFC. If not provided, only POSIX rules are applied.
+    #[clap(long, value_enum, default_value = "posix")]
+    sanitize: SanitizeProfile,
+    /// The maximum length, in bytes, of every generated file and directory name.
+    #[clap(long, default_value_t = 255)]
+    max_name_bytes: usize,
+    /// The ebook formats to process, separated by commas. If not provided, every supported
+    /// format is processed.
+    #[clap(
+        short,
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "epub,pdf,mobi,cbz,cbr,fb2"
+    )]
+    formats: Vec<Format>,
+    /// A regular expression matched against the file name, without its extension, of ebooks
+    /// whose metadata is missing a title, author, series, series index or year. Named groups
+    /// such as `(?P<author>.+) - (?P<title>.+)` fill in the fields they capture, and the first
+    /// pattern which matches wins. Can be given more than once, and is tried before the built-in
+    /// patterns for names like `Author - Series 03 - Title` or `Author - Title (Series #3)`.
+    #[clap(long)]
+    filename_pattern: Vec<FilenamePattern>,
+    /// Only use the patterns given with `--filename-pattern`, never the built-in ones.
+    #[clap(long)]
+    no_builtin_patterns: bool,
+    /// Hash every ebook and group copies of the same book, either with identical contents or
+    /// sharing an ISBN or normalized title and author, then `report` them, `keep-first` or
+    /// `keep-largest` copy and leave the others in place, or `move-to` the extra copies into
+    /// `--duplicates-dir`. If not provided, duplicates are not detected.
+    #[clap(long, value_enum)]
+    duplicates: Option<DuplicatePolicy>,
+    /// The directory extra copies are moved into with `--duplicates move-to`.
+    #[clap(long, required_if_eq("duplicates", "move-to"))]
+    duplicates_dir: Option<PathBuf>,
+    /// The number of ebooks to read at the same time. If not provided, one ebook is read per
+    /// CPU core.
+    #[clap(short, long)]
+    jobs: Option<usize>,
+    /// Ignore the library index and read every ebook again. Unchanged ebooks are otherwise
+    /// served from the index kept in the output directory, so only new or changed files are
+    /// opened.
+    #[clap(long)]
+    rebuild_index: bool,
+    /// Scan the library and print the planned placements without creating any directories
+    /// or moving any files.
+    #[clap(long)]
+    dry_run: bool,
+    /// Rewrite the title, creators and series inside every placed EPUB to match the metadata
+    /// used to sort it, after keeping a backup of the original next to the run's journal. Only
+    /// applies to the move, copy and reflink strategies, since links share the original file.
+    #[clap(long)]
+    write_metadata: bool,
+    /// Print the dry-run plan as JSON instead of a table.
+    #[clap(long, requires = "dry_run")]
+    json: bool,
+    /// Write a report of the run to this file, listing every processed ebook with its
+    /// destination, the action taken, the metadata used, how long it took and any error,
+    /// followed by the run's totals.
+    #[clap(long)]
+    report: Option<PathBuf>,
+    /// The format of the `--report` file.
+    #[clap(long, value_enum, default_value = "json")]
+    report_format: ReportFormat,
+    /// Only print errors, leaving out the results and the summary.
+    #[clap(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// List every placement in the results, not only the ones that needed attention.
+    #[clap(short, long)]
+    verbose: bool,
 }
 
-#[derive(Debug, thiserror::Error)]
-enum EbookSortError {
-    #[error("Failed to parse file as an EPUB document")]
-    InvalidEbook { path: PathBuf, error: String },
-    #[error("Failed to perform IO operation: {0}")]
-    IoError(std::io::Error),
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Revert a previous run by replaying its journal in reverse.
+    Undo {
+        /// The journal to replay. If not provided, the most recent journal in the output
+        /// directory is used.
+        journal: Option<PathBuf>,
+    },
+    /// Read every EPUB in the root directory and report the books with missing or junk
+    /// metadata: no title, creator, language or identifier, titles which look like file names,
+    /// placeholder creators such as `Unknown` or `calibre`, and ISBNs with a bad checksum.
+    Audit {
+        /// Also write every finding to this CSV file.
+        #[clap(long)]
+        csv: Option<PathBuf>,
+    },
+    /// Inspect the config file.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Watch the root directory and sort every ebook added to it, once it has been completely
+    /// written. Runs until interrupted.
+    Watch {
+        /// How many seconds a file's size must stay the same before it is considered complete,
+        /// for writers which don't close the file in a way that can be detected.
+        #[clap(long, default_value_t = 2)]
+        settle: u64,
+    },
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Print the effective settings, after merging the config file and command line.
+    Show,
+}
+
+fn main() -> Result<ExitCode> {
+    let (args, config) = config::parse_args()?;
 
     let root = match args.root {
-        Some(root) => root,
+        Some(ref root) => root.clone(),
         _ => std::env::current_dir()?,
     };
-    let output = args.output.unwrap_or_else(|| root.clone());
+    let output = args.output.clone().unwrap_or_else(|| root.clone());
+
+    match args.command {
+        Some(Command::Undo { ref journal }) => undo(journal.clone(), &output),
+        Some(Command::Audit { ref csv }) => audit::audit(&args, &root, &output, csv.as_deref()),
+        Some(Command::Config {
+            command: ConfigCommand::Show,
+        }) => {
+            match config {
+                Some(source) => println!(
+                    "# Loaded from {}{}",
+                    source.path.display(),
+                    source
+                        .profile
+                        .map(|profile| format!(", profile {profile}"))
+                        .unwrap_or_default()
+                ),
+                None => println!("# No config file found"),
+            }
+            let settings = config::Settings::from_args(&args, &root, &output);
+            print!("{}", toml::to_string(&settings)?);
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::Watch { settle }) => {
+            watch::watch(&args, root, output, Duration::from_secs(settle))?;
+            Ok(ExitCode::SUCCESS)
+        }
+        None => sort(&args, root, output),
+    }
+}
 
-    let mut errors = Vec::new();
+fn sort(args: &Args, root: PathBuf, output: PathBuf) -> Result<ExitCode> {
+    let mut report = Report::start();
+    // Checked before the index is opened, which would create the output directory
+    if !root.is_dir() {
+        return Err(eyre::eyre!("{} is not a directory", root.display()));
+    }
+    let mut pipeline = Pipeline::open(args, output.clone())?;
 
-    let total_files = WalkDir::new(&root)
+    // The library is walked once, up front, into a work list. Nothing is placed until the walk
+    // is over, so files moved into an output directory inside root are never rediscovered.
+    let spinner = ProgressBar::new_spinner().with_style(
+        ProgressStyle::default_spinner().template("{spinner} Looking for ebooks... {pos} found")?,
+    );
+    if args.quiet {
+        spinner.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    let excluded = nested_output(&root, &output);
+    let journal_dir = journal_dir(&output);
+    let mut work = Vec::new();
+    let mut walk_errors = Vec::new();
+    let entries = WalkDir::new(&root)
         .min_depth(0)
+        .sort_by_file_name()
         .into_iter()
-        .filter_map(Result::ok)
-        .filter(|entry| entry.path().extension() == Some("epub".as_ref()))
-        .count();
-    let bar = ProgressBar::new(total_files as u64).with_style(
+        .filter_entry(|entry| {
+            Some(entry.path()) != excluded.as_deref()
+                && !is_journal_dir(entry.path(), journal_dir.as_deref())
+        });
+    for entry in entries {
+        match entry {
+            Ok(entry) => {
+                if let Some(format) = wanted_format(args, entry.path()) {
+                    work.push((format, entry.into_path()));
+                    spinner.inc(1);
+                }
+            }
+            Err(e) => {
+                let path = e.path().unwrap_or(&root).to_path_buf();
+                let message = e.to_string();
+                let error = e
+                    .into_io_error()
+                    .unwrap_or_else(|| io::Error::other(message));
+                walk_errors.push(EbookSortError::io(Stage::Scan, &path, None, error));
+            }
+        }
+    }
+    spinner.finish_and_clear();
+
+    let bar = ProgressBar::new(work.len() as u64).with_style(
         ProgressStyle::default_bar()
             .template("{msg}\n{wide_bar:.cyan/blue} {pos}/{len} {percent}%")?,
     );
+    if args.quiet {
+        bar.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    let mut plan = pipeline.plan(work, &bar)?;
+    plan.errors.extend(walk_errors);
+    bar.finish();
 
-    let walker = WalkDir::new(root).min_depth(0);
+    for group in &plan.duplicates {
+        for member in &group.members {
+            if member.action == DuplicateAction::Skip {
+                let scan = plan.scans.get(&member.path);
+                report.add(
+                    &member.path,
+                    None,
+                    Action::DuplicateSkipped,
+                    scan,
+                    Duration::ZERO,
+                );
+            }
+        }
+    }
 
-    for entry in walker.into_iter().filter_map(Result::ok) {
-        let Some(extension) = entry.path().extension() else {
-            continue;
-        };
+    for error in &plan.errors {
+        report.add_error(error, plan.scans.get(error.path()));
+    }
+
+    if args.dry_run {
+        for placement in &plan.placements {
+            let action = match placement.is_in_place() {
+                true => Action::AlreadySorted,
+                false => Action::Planned,
+            };
+            let scan = plan.scans.get(&placement.source);
+            report.add(
+                &placement.source,
+                Some(&placement.destination),
+                action,
+                scan,
+                Duration::ZERO,
+            );
+        }
+        write_report(args, &mut report, &root, &output)?;
+
+        if args.json {
+            println!("{}", plan.to_json()?);
+        } else if args.quiet {
+            for error in &plan.errors {
+                eprintln!(
+                    "Failed to {} {}: {}",
+                    error.stage(),
+                    error.path().display(),
+                    error.message()
+                );
+            }
+        } else {
+            let already_sorted = plan.placements.iter().filter(|p| p.is_in_place()).count();
+            println!("{}", plan.to_table());
+            println!(
+                "{} to sort, {already_sorted} already sorted, {} errors",
+                plan.placements.len() - already_sorted,
+                plan.errors.len()
+            );
+        }
+        return Ok(exit_code(&plan.errors));
+    }
+
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL)
+        .apply_modifier(UTF8_ROUND_CORNERS)
+        .set_content_arrangement(ContentArrangement::Dynamic)
+        .set_header(vec!["Result", "Path", "Destination"]);
+
+    let mut journal = Journal::create(&output)?;
+    let mut authors_created = HashSet::new();
 
-        if extension != "epub" {
+    for placement in &plan.placements {
+        let (source, destination) = (&placement.source, &placement.destination);
+        let scan = plan.scans.get(source);
+        if placement.is_in_place() {
+            report.add(
+                source,
+                Some(destination),
+                Action::AlreadySorted,
+                scan,
+                Duration::ZERO,
+            );
+            if args.verbose {
+                table.add_row(vec![
+                    Outcome::AlreadyInPlace.to_string(),
+                    source.to_string_lossy().to_string(),
+                    destination.to_string_lossy().to_string(),
+                ]);
+            }
             continue;
         }
 
-        bar.set_message(format!(
-            "{}",
-            entry.path().file_name().unwrap().to_string_lossy()
-        ));
+        // The directory directly below the output, which is the author's with the default
+        // template, if placing the ebook creates it
+        let new_top = destination
+            .strip_prefix(&output)
+            .ok()
+            .and_then(|relative| relative.components().next())
+            .map(|top| output.join(top))
+            .filter(|top| top != destination && !top.exists());
 
-        let book = match EpubDoc::new(entry.path()) {
-            Ok(book) => book,
+        let started = Instant::now();
+        match pipeline.place(
+            placement,
+            scan.and_then(|s| s.metadata.as_ref()),
+            &mut journal,
+        ) {
+            Ok(outcome) => {
+                let placed_at = outcome.placed_at(destination);
+                if let (Some(_), Some(top)) = (placed_at, new_top) {
+                    authors_created.insert(top);
+                }
+                report.add(
+                    source,
+                    placed_at,
+                    (&outcome).into(),
+                    scan,
+                    started.elapsed(),
+                );
+                if args.verbose || outcome.is_notable() {
+                    table.add_row(vec![
+                        outcome.to_string(),
+                        source.to_string_lossy().to_string(),
+                        destination.to_string_lossy().to_string(),
+                    ]);
+                }
+            }
             Err(e) => {
-                errors.push(EbookSortError::InvalidEbook {
-                    path: entry.path().to_path_buf(),
-                    error: e.to_string(),
-                });
-                bar.inc(1);
-                continue;
+                report.add_error(&e, scan);
+                plan.errors.push(e);
             }
+        }
+    }
+
+    for group in &plan.duplicates {
+        table.add_row(vec![
+            format!("duplicate: {}", group.kind),
+            group.members_cell(),
+            String::default(),
+        ]);
+    }
+
+    for error in &plan.errors {
+        table.add_row(vec![
+            format!("error ({}): {}", error.stage(), error.message()),
+            error.path().to_string_lossy().to_string(),
+            error
+                .destination()
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        ]);
+    }
+
+    if args.quiet {
+        for error in &plan.errors {
+            eprintln!(
+                "Failed to {} {}: {}",
+                error.stage(),
+                error.path().display(),
+                error.message()
+            );
+        }
+    } else {
+        if !table.is_empty() {
+            println!("{table}");
+        }
+        let summary = report.to_table(&args.strategy, plan.duplicates.len(), authors_created.len());
+        println!("{summary}");
+        if !journal.is_empty() {
+            println!("Journal written to {}", journal.path().display());
+        }
+    }
+    write_report(args, &mut report, &root, &output)?;
+
+    Ok(exit_code(&plan.errors))
+}
+
+/// The extraction and placement steps shared by a one-off sort and watch mode
+struct Pipeline<'a> {
+    args: &'a Args,
+    output: PathBuf,
+    normalizer: AuthorNormalizer,
+    /// The library index, which is read-only during dry runs
+    index: Option<LibraryIndex>,
+    snapshot: IndexSnapshot,
+    pool: rayon::ThreadPool,
+}
+
+impl<'a> Pipeline<'a> {
+    fn open(args: &'a Args, output: PathBuf) -> Result<Pipeline<'a>> {
+        let normalizer = match args.aliases {
+            Some(ref aliases) => AuthorNormalizer::from_alias_file(aliases)?,
+            None => AuthorNormalizer::default(),
         };
 
-        let creator = book
-            .metadata
-            .get("creator")
-            .map(|c| c.join(", "))
-            .unwrap_or("Unsorted".to_string());
-
-        let author_dir = output.join(creator);
-        if !author_dir.exists() {
-            if let Err(e) = std::fs::create_dir_all(&author_dir) {
-                errors.push(EbookSortError::IoError(e));
-                bar.inc(1);
-                continue;
+        // Dry runs read the index but never write to the output directory
+        let index = match args.dry_run {
+            true => LibraryIndex::open_read_only(&output)?,
+            false => Some(LibraryIndex::open(&output)?),
+        };
+        let snapshot = match index {
+            Some(ref index) if !args.rebuild_index => index.load()?,
+            Some(ref index) if !args.dry_run => {
+                index.clear()?;
+                IndexSnapshot::default()
             }
+            _ => IndexSnapshot::default(),
+        };
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(args.jobs.unwrap_or_default())
+            .build()?;
+
+        Ok(Pipeline {
+            args,
+            output,
+            normalizer,
+            index,
+            snapshot,
+            pool,
+        })
+    }
+
+    /// Read every ebook in the work list and plan where it goes
+    fn plan(&mut self, work: Vec<(Format, PathBuf)>, bar: &ProgressBar) -> Result<Plan> {
+        let args = self.args;
+        let mut plan = Plan {
+            multi_author: args.multi_author,
+            ..Default::default()
+        };
+        let mut duplicates = DuplicateFinder::default();
+
+        // Books are read in parallel, but only planned here, so placement stays on a single
+        // thread and no two workers can ever claim the same destination
+        let scanned = self.pool.install(|| {
+            work.into_par_iter()
+                .map(|(format, path)| {
+                    bar.set_message(format!("{}", path.file_name().unwrap().to_string_lossy()));
+                    let started = Instant::now();
+                    let scanned = scan(
+                        args,
+                        &self.snapshot,
+                        &self.normalizer,
+                        &self.output,
+                        format,
+                        &path,
+                    );
+                    bar.inc(1);
+                    (path, scanned, started.elapsed())
+                })
+                .collect::<Vec<_>>()
+        });
+
+        if let Some(index) = self.index.as_mut().filter(|_| !args.dry_run) {
+            index.store(scanned.iter().filter_map(|(path, scanned, _)| {
+                let entry = scanned.as_ref().ok()?.indexed.as_ref()?;
+                Some((path.as_path(), entry))
+            }))?;
         }
-        let filename = format_book(&book, &entry);
-        let destination = author_dir.join(filename);
 
-        match args.strategy {
-            PlaceStrategy::Copy => {
-                if let Err(e) = std::fs::copy(entry.path(), &destination) {
-                    errors.push(EbookSortError::IoError(e));
-                    continue;
+        for (path, scanned, duration) in scanned {
+            let (metadata, size) = match scanned {
+                Ok(book) => {
+                    if args.duplicates.is_some() {
+                        duplicates.add(&path, book.size, book.hash, &book.metadata);
+                    }
+                    plan.placements.push(book.placement);
+                    (Some(book.metadata), Some(book.size))
                 }
+                Err(e) => {
+                    plan.errors.push(e);
+                    (None, None)
+                }
+            };
+            let scan = ScanInfo {
+                metadata,
+                size,
+                duration,
+            };
+            plan.scans.insert(path, scan);
+        }
+
+        if let Some(policy) = args.duplicates {
+            plan.apply_duplicates(duplicates.groups(policy, args.duplicates_dir.as_deref()));
+        }
+
+        Ok(plan)
+    }
+
+    /// Place a single ebook, recording every change in the journal. With `--write-metadata`,
+    /// the placed EPUB is then rewritten to carry `metadata`.
+    fn place(
+        &self,
+        placement: &Placement,
+        metadata: Option<&BookMetadata>,
+        journal: &mut Journal,
+    ) -> Result<Outcome, EbookSortError> {
+        let args = self.args;
+        let failed = |stage| {
+            move |e| EbookSortError::io(stage, &placement.source, Some(&placement.destination), e)
+        };
+
+        for path in place::create_parents(&placement.destination).map_err(failed(Stage::Mkdir))? {
+            journal
+                .record(&JournalEntry::Mkdir { path })
+                .map_err(failed(Stage::Mkdir))?;
+        }
+
+        // Extra copies sent to the duplicates directory are always moved, since copying or
+        // linking them would leave them where they were
+        let strategy = match placement.duplicate {
+            true => &PlaceStrategy::Move,
+            false => &args.strategy,
+        };
+
+        // A file which gets overwritten is kept, so that undo can put it back
+        let backup = journal.backup_path(&placement.destination);
+        let outcome = place::place(placement, strategy, args.on_conflict, &backup)
+            .map_err(failed(Stage::Place))?;
+        if let Some(placed_at) = outcome.placed_at(&placement.destination) {
+            let replaced = (outcome == Outcome::Overwritten).then_some(backup.as_path());
+            journal
+                .record_place(&placement.source, placed_at, strategy, replaced)
+                .map_err(failed(Stage::Place))?;
+            if let Some(ref index) = self.index {
+                // The index is only a cache, so an entry which could not be updated just means
+                // the book is parsed again next time
+                let _ = index.record_placement(&placement.source, placed_at, strategy);
             }
-            PlaceStrategy::Move => {
-                if let Err(e) = std::fs::rename(entry.path(), &destination) {
-                    errors.push(EbookSortError::IoError(e));
-                    continue;
+
+            let writable = !placement.duplicate
+                && matches!(
+                    args.strategy,
+                    PlaceStrategy::Move | PlaceStrategy::Copy | PlaceStrategy::Reflink
+                );
+            if let Some(metadata) = metadata.filter(|_| args.write_metadata && writable) {
+                if Format::from_path(placed_at) == Some(Format::Epub) {
+                    let backup = journal.backup_path(placed_at);
+                    let written = formats::write_metadata(placed_at, metadata, &backup)
+                        .map_err(failed(Stage::WriteMetadata))?;
+                    if written {
+                        let hash =
+                            hash::hash_file(placed_at).map_err(failed(Stage::WriteMetadata))?;
+                        journal
+                            .record(&JournalEntry::WriteMetadata {
+                                path: placed_at.to_path_buf(),
+                                backup,
+                                hash: hash.to_hex().to_string(),
+                            })
+                            .map_err(failed(Stage::WriteMetadata))?;
+                    }
                 }
             }
         }
+        Ok(outcome)
+    }
+}
 
-        bar.inc(1);
+/// Write the run report, when one was asked for
+fn write_report(args: &Args, report: &mut Report, root: &Path, output: &Path) -> Result<()> {
+    let Some(ref path) = args.report else {
+        return Ok(());
+    };
+    let summary = report.summary(root, output, &args.strategy, args.dry_run);
+    report
+        .write(path, args.report_format, &summary)
+        .map_err(|e| eyre::eyre!("Failed to write report {}: {e}", path.display()))
+}
+
+/// Success when every ebook went through, or the partial failure code when some did not
+fn exit_code(errors: &[EbookSortError]) -> ExitCode {
+    match errors.is_empty() {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::from(EXIT_PARTIAL),
     }
+}
 
-    bar.finish();
+/// Revert the changes recorded in a journal, most recent first
+fn undo(journal: Option<PathBuf>, output: &Path) -> Result<ExitCode> {
+    let path = match journal {
+        Some(path) => path,
+        None => journal::latest(output)?.ok_or_else(|| {
+            eyre::eyre!(
+                "No journal found in {}",
+                output.join(journal::JOURNAL_DIR).display()
+            )
+        })?,
+    };
+
+    let entries = journal::read(&path)?;
 
     let mut table = Table::new();
     table
         .load_preset(UTF8_FULL)
         .apply_modifier(UTF8_ROUND_CORNERS)
         .set_content_arrangement(ContentArrangement::Dynamic)
-        .set_header(vec!["Error", "Path"]);
+        .set_header(vec!["Result", "Path", "Restored To"]);
 
-    for error in errors {
-        match error {
-            EbookSortError::InvalidEbook { path, error } => {
-                table.add_row(vec![error, path.to_string_lossy().to_string()]);
-            }
-            EbookSortError::IoError(e) => {
-                table.add_row(vec![e.to_string(), String::default()]);
-            }
-        }
+    let mut complete = true;
+    for entry in entries.iter().rev() {
+        let outcome = journal::revert(entry);
+        complete &= outcome.is_success();
+
+        let (path, restored_to) = match entry {
+            JournalEntry::Mkdir { path } => (path, String::default()),
+            JournalEntry::Place {
+                source,
+                destination,
+                strategy: PlaceStrategy::Move,
+                ..
+            } => (destination, source.to_string_lossy().to_string()),
+            JournalEntry::Place { destination, .. } => (destination, String::default()),
+            JournalEntry::WriteMetadata { path, .. } => (path, String::default()),
+        };
+        table.add_row(vec![
+            outcome.to_string(),
+            path.to_string_lossy().to_string(),
+            restored_to,
+        ]);
     }
 
     println!("{table}");
 
-    Ok(())
+    if complete {
+        let undone = journal::mark_undone(&path)?;
+        println!("Journal marked as undone: {}", undone.display());
+        Ok(ExitCode::SUCCESS)
+    } else {
+        println!(
+            "Some entries could not be reverted, the journal was left at {}",
+            path.display()
+        );
+        Ok(ExitCode::from(EXIT_PARTIAL))
+    }
+}
+
+/// A book read by one of the scan workers
+struct Scanned {
+    metadata: BookMetadata,
+    placement: Placement,
+    size: u64,
+    hash: blake3::Hash,
+    /// A new index entry, when the file was not indexed yet or has changed since
+    indexed: Option<IndexEntry>,
+}
+
+/// Read a book's metadata, from the index when the file hasn't changed, and plan its placement
+fn scan(
+    args: &Args,
+    snapshot: &IndexSnapshot,
+    normalizer: &AuthorNormalizer,
+    output: &Path,
+    format: Format,
+    path: &Path,
+) -> Result<Scanned, EbookSortError> {
+    let scan_error = |e| EbookSortError::io(Stage::Scan, path, None, e);
+    let stamp = FileStamp::of(path).map_err(scan_error)?;
+    let (mut metadata, hash, indexed) = match snapshot.get(path, stamp) {
+        Some(entry) => (entry.metadata.clone(), entry.hash, None),
+        None => {
+            let hash = hash::hash_file(path).map_err(scan_error)?;
+            // A file which was only touched, or moved by another program, is not parsed again
+            let metadata = match snapshot.get_by_hash(&hash) {
+                Some(entry) => entry.metadata.clone(),
+                None => format.reader().read(path).map_err(|e| match e {
+                    ReadError::Io(e) => EbookSortError::io(Stage::Parse, path, None, e),
+                    ReadError::Invalid(error) => EbookSortError::InvalidEbook {
+                        path: path.to_path_buf(),
+                        error,
+                    },
+                })?,
+            };
+            let entry = IndexEntry {
+                stamp,
+                hash,
+                metadata: metadata.clone(),
+            };
+            (metadata, hash, Some(entry))
+        }
+    };
+    let placement_for = |metadata: &BookMetadata, sources| {
+        let context = render_context(args, metadata, path);
+        Placement {
+            source: path.to_path_buf(),
+            destination: output.join(destination_for(args, &context)),
+            author: context.author(),
+            sources,
+            duplicate: false,
+        }
+    };
+
+    // A book already where its own metadata puts it keeps its place: its file name was most
+    // likely written by a previous run, and reading fields back from it would move it again
+    let mut own_metadata = metadata.clone();
+    normalizer.normalize(&mut own_metadata);
+    let placement = placement_for(&own_metadata, filename::sources(&own_metadata));
+    let (metadata, placement) = match placement.is_in_place() {
+        true => (own_metadata, placement),
+        false => {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let sources = filename::fill_missing(
+                &mut metadata,
+                &stem,
+                &args.filename_pattern,
+                !args.no_builtin_patterns,
+            );
+            normalizer.normalize(&mut metadata);
+            let placement = placement_for(&metadata, sources);
+            (metadata, placement)
+        }
+    };
+
+    Ok(Scanned {
+        metadata,
+        placement,
+        size: stamp.size,
+        hash,
+        indexed,
+    })
+}
+
+/// The output directory, as it appears in the walk of root, when it is a subdirectory of root.
+/// It holds books which were already sorted, so it is left out of the walk.
+fn nested_output(root: &Path, output: &Path) -> Option<PathBuf> {
+    let root_dir = root.canonicalize().ok()?;
+    let relative = output.canonicalize().ok()?;
+    let relative = relative.strip_prefix(&root_dir).ok()?;
+    (!relative.as_os_str().is_empty()).then(|| root.join(relative))
 }
 
-fn format_book(book: &EpubDoc<BufReader<File>>, entry: &DirEntry) -> String {
-    match book.metadata.get("title").and_then(|t| t.first().cloned()) {
-        Some(title) => format!("{}.epub", title.trim()),
-        _ => entry.file_name().to_string_lossy().trim().to_string(),
+/// The directory journals and metadata backups are kept in, when it exists
+fn journal_dir(output: &Path) -> Option<PathBuf> {
+    output.join(journal::JOURNAL_DIR).canonicalize().ok()
+}
+
+/// Whether a path found while walking root is the journal directory, which holds backups of
+/// ebooks that must never be sorted as books of their own
+fn is_journal_dir(path: &Path, journal_dir: Option<&Path>) -> bool {
+    path.file_name() == Some(journal::JOURNAL_DIR.as_ref())
+        && journal_dir.is_some_and(|dir| path.canonicalize().is_ok_and(|path| path == dir))
+}
+
+/// The format of a file, if it is one of the formats selected for this run
+fn wanted_format(args: &Args, path: &Path) -> Option<Format> {
+    Format::from_path(path).filter(|format| args.formats.contains(format))
+}
+
+/// The values the template is rendered with for a single book
+fn render_context<'a>(args: &Args, metadata: &'a BookMetadata, path: &Path) -> RenderContext<'a> {
+    RenderContext {
+        metadata,
+        authors: AuthorPolicy {
+            form: args.author_form,
+            multi_author: args.multi_author,
+            max_authors: args.max_authors,
+        },
+        extension: path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        stem: path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().trim().to_string())
+            .unwrap_or_default(),
     }
 }
+
+/// Render the template for a book into a sanitized path relative to the output directory
+fn destination_for(args: &Args, context: &RenderContext) -> PathBuf {
+    let components = args.template.render(context);
+    let last = components.len().saturating_sub(1);
+    components
+        .iter()
+        .enumerate()
+        .map(|(i, component)| {
+            sanitize::sanitize_component(component, args.sanitize, args.max_name_bytes, i == last)
+        })
+        .collect()
+}
@@ -0,0 +1,176 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::formats::RawMetadata;
+
+/// A person credited on a book, such as an author, editor or illustrator
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contributor {
+    pub name: String,
+    /// The name in sort order, e.g. `Herbert, Frank`, when the book provides one
+    pub file_as: Option<String>,
+    /// MARC relator codes describing the contribution, e.g. `aut`, `edt` or `ill`
+    pub roles: Vec<String>,
+}
+
+impl Contributor {
+    pub fn new(name: impl Into<String>) -> Contributor {
+        Contributor {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the contributor is credited with a MARC relator code, e.g. `aut`
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r.eq_ignore_ascii_case(role))
+    }
+}
+
+/// A unique identifier for a book, such as an ISBN or UUID
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identifier {
+    /// The identifier scheme, lowercased, e.g. `isbn`, `uuid` or `asin`
+    pub scheme: Option<String>,
+    pub value: String,
+}
+
+impl Identifier {
+    /// Build an identifier, inferring the scheme from `urn:` prefixes or the value's shape
+    /// when none is given
+    pub fn new(scheme: Option<&str>, value: &str) -> Identifier {
+        let value = value.trim();
+        let lower = value.to_lowercase();
+
+        for prefix in [
+            "urn:isbn:",
+            "isbn:",
+            "urn:uuid:",
+            "uuid:",
+            "urn:asin:",
+            "asin:",
+        ] {
+            if lower.starts_with(prefix) {
+                let scheme = prefix.trim_start_matches("urn:").trim_end_matches(':');
+                return Identifier {
+                    scheme: Some(scheme.to_string()),
+                    value: value[prefix.len()..].trim().to_string(),
+                };
+            }
+        }
+
+        let scheme = scheme
+            .map(|s| s.trim().to_lowercase())
+            .filter(|s| !s.is_empty())
+            .or_else(|| {
+                let digits = value.chars().filter(|c| !matches!(c, '-' | ' ')).count();
+                let is_isbn_like = value
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || matches!(c, '-' | ' ' | 'X' | 'x'));
+                (is_isbn_like && (digits == 10 || digits == 13)).then(|| "isbn".to_string())
+            });
+
+        Identifier {
+            scheme,
+            value: value.to_string(),
+        }
+    }
+
+    pub fn is_isbn(&self) -> bool {
+        self.scheme.as_deref() == Some("isbn")
+    }
+}
+
+/// Everything the program knows about a book, normalized across formats.
+///
+/// This is the single source of truth used for placement, templates and reports.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BookMetadata {
+    /// Every title of the book, with the main title first
+    pub titles: Vec<String>,
+    /// The book's creators, in the order they should be credited
+    pub authors: Vec<Contributor>,
+    /// Secondary contributors, such as translators
+    pub contributors: Vec<Contributor>,
+    pub series: Option<String>,
+    pub series_index: Option<f64>,
+    pub languages: Vec<String>,
+    pub publisher: Option<String>,
+    /// The publication date as written in the book, ideally `YYYY`, `YYYY-MM` or `YYYY-MM-DD`
+    pub published: Option<String>,
+    pub identifiers: Vec<Identifier>,
+    pub subjects: Vec<String>,
+    pub description: Option<String>,
+    /// Any other Dublin Core elements, such as `rights` or `source`
+    pub other: BTreeMap<String, Vec<String>>,
+}
+
+impl BookMetadata {
+    /// Build metadata from Dublin Core keyed values, as produced by the non-EPUB readers
+    pub fn from_raw(raw: RawMetadata) -> BookMetadata {
+        let mut metadata = BookMetadata::default();
+
+        for (key, values) in raw {
+            let values = values
+                .into_iter()
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .collect::<Vec<_>>();
+            let first = values.first().cloned();
+
+            match key.as_str() {
+                "title" => metadata.titles = values,
+                "creator" => metadata.authors = values.into_iter().map(Contributor::new).collect(),
+                "contributor" => {
+                    metadata.contributors = values.into_iter().map(Contributor::new).collect()
+                }
+                "calibre:series" => metadata.series = first,
+                "calibre:series_index" => {
+                    metadata.series_index = first.and_then(|index| parse_index(&index))
+                }
+                "language" => metadata.languages = values,
+                "publisher" => metadata.publisher = first,
+                "date" => metadata.published = first,
+                "identifier" => {
+                    metadata.identifiers = values
+                        .iter()
+                        .map(|value| Identifier::new(None, value))
+                        .collect()
+                }
+                "subject" => metadata.subjects = values,
+                "description" => metadata.description = first,
+                _ if !values.is_empty() => {
+                    metadata.other.insert(key, values);
+                }
+                _ => {}
+            }
+        }
+
+        metadata
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.titles.first().map(String::as_str)
+    }
+
+    /// The publication year, if the publication date starts with one
+    pub fn year(&self) -> Option<&str> {
+        let date = self.published.as_deref()?;
+        let year = date.get(..4)?;
+        year.chars().all(|c| c.is_ascii_digit()).then_some(year)
+    }
+
+    pub fn isbn(&self) -> Option<&Identifier> {
+        self.identifiers.iter().find(|id| id.is_isbn())
+    }
+
+    /// The first value of any other Dublin Core element
+    pub fn other(&self, key: &str) -> Option<&str> {
+        self.other.get(key)?.first().map(String::as_str)
+    }
+}
+
+/// Parse a series index such as `3` or `2.5`
+pub fn parse_index(index: &str) -> Option<f64> {
+    index.trim().parse::<f64>().ok().filter(|i| i.is_finite())
+}
@@ -0,0 +1,365 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{hash, plan::Placement};
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaceStrategy {
+    Copy,
+    #[default]
+    Move,
+    /// Hard link the ebook into place, which requires both paths to be on the same filesystem
+    Hardlink,
+    /// Place a symbolic link to the ebook's absolute path
+    Symlink,
+    /// Place a symbolic link to the ebook's path relative to the destination, which keeps
+    /// working when the whole tree is moved or mounted elsewhere
+    RelativeSymlink,
+    /// Clone the ebook with copy-on-write on filesystems which support it, such as btrfs, XFS
+    /// and APFS, and copy it everywhere else
+    Reflink,
+}
+
+/// What to do when a file already exists at an ebook's destination
+#[derive(ValueEnum, Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Leave both files untouched
+    #[default]
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Place the ebook next to the existing file with a numeric suffix, e.g. `Dune (2).epub`
+    Rename,
+    /// Keep whichever file is larger
+    KeepLarger,
+    /// Keep whichever file was modified most recently
+    KeepNewer,
+    /// Skip the ebook if it is byte-identical to the existing file, otherwise rename it
+    CompareHash,
+}
+
+/// The result of placing a single ebook
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The ebook was placed at its destination without any conflict
+    Placed,
+    /// The ebook is already at its destination
+    AlreadyInPlace,
+    /// The destination existed and was left untouched
+    Skipped,
+    /// The destination existed and was replaced
+    Overwritten,
+    /// The destination existed, so the ebook was placed at an alternative path instead
+    Renamed(PathBuf),
+    /// The destination existed and was kept because it is larger or newer
+    KeptExisting,
+    /// The destination existed and was byte-identical to the ebook
+    Identical,
+}
+
+impl Outcome {
+    /// Whether the outcome is worth surfacing in the final report
+    pub fn is_notable(&self) -> bool {
+        !matches!(self, Outcome::Placed | Outcome::AlreadyInPlace)
+    }
+
+    /// Where the ebook ended up, if this outcome placed it anywhere
+    pub fn placed_at<'a>(&'a self, destination: &'a Path) -> Option<&'a Path> {
+        match self {
+            Outcome::Placed | Outcome::Overwritten => Some(destination),
+            Outcome::Renamed(path) => Some(path),
+            Outcome::AlreadyInPlace
+            | Outcome::Skipped
+            | Outcome::KeptExisting
+            | Outcome::Identical => None,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Placed => write!(f, "Placed"),
+            Outcome::AlreadyInPlace => write!(f, "Already in place"),
+            Outcome::Skipped => write!(f, "Skipped, destination exists"),
+            Outcome::Overwritten => write!(f, "Overwrote existing file"),
+            Outcome::Renamed(path) => write!(
+                f,
+                "Renamed to {}",
+                path.file_name().unwrap_or_default().to_string_lossy()
+            ),
+            Outcome::KeptExisting => write!(f, "Kept existing file"),
+            Outcome::Identical => write!(f, "Skipped, identical file exists"),
+        }
+    }
+}
+
+/// Create any missing ancestors of the destination, returning the directories which were
+/// created, outermost first
+pub fn create_parents(destination: &Path) -> io::Result<Vec<PathBuf>> {
+    let Some(parent) = destination.parent() else {
+        return Ok(Vec::new());
+    };
+
+    let mut missing = parent
+        .ancestors()
+        .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+        .map(Path::to_path_buf)
+        .collect::<Vec<_>>();
+    missing.reverse();
+
+    if !missing.is_empty() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    Ok(missing)
+}
+
+/// Place the ebook at its destination according to the chosen strategy, resolving any
+/// existing file with `policy`. A file which is overwritten is moved to `backup` first. The
+/// destination's parent directory must already exist.
+pub fn place(
+    placement: &Placement,
+    strategy: &PlaceStrategy,
+    policy: ConflictPolicy,
+    backup: &Path,
+) -> io::Result<Outcome> {
+    let source = &placement.source;
+    let destination = &placement.destination;
+
+    // A link placed by an earlier run leads back to the ebook itself
+    if source == destination || same_file(source, destination) {
+        return Ok(Outcome::AlreadyInPlace);
+    }
+
+    if !destination.exists() {
+        transfer(source, destination, strategy)?;
+        return Ok(Outcome::Placed);
+    }
+
+    let replace = match policy {
+        ConflictPolicy::Skip => return Ok(Outcome::Skipped),
+        ConflictPolicy::Overwrite => true,
+        ConflictPolicy::Rename => return rename_alongside(source, destination, strategy),
+        ConflictPolicy::KeepLarger => source.metadata()?.len() > destination.metadata()?.len(),
+        ConflictPolicy::KeepNewer => {
+            source.metadata()?.modified()? > destination.metadata()?.modified()?
+        }
+        ConflictPolicy::CompareHash => {
+            if hash::same_contents(source, destination)? {
+                return Ok(Outcome::Identical);
+            }
+            return rename_alongside(source, destination, strategy);
+        }
+    };
+
+    if replace {
+        if let Some(parent) = backup.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        move_file(destination, backup)?;
+        if let Err(e) = transfer(source, destination, strategy) {
+            let _ = move_file(backup, destination);
+            return Err(e);
+        }
+        Ok(Outcome::Overwritten)
+    } else {
+        Ok(Outcome::KeptExisting)
+    }
+}
+
+fn transfer(source: &Path, destination: &Path, strategy: &PlaceStrategy) -> io::Result<()> {
+    match strategy {
+        PlaceStrategy::Copy => std::fs::copy(source, destination).map(|_| ()),
+        PlaceStrategy::Move => move_file(source, destination),
+        // Links and clones can't replace an existing file, so they are created under a temporary
+        // name and renamed over the destination
+        strategy => {
+            let partial = partial_path(destination);
+            // Left behind by an interrupted run
+            let _ = std::fs::remove_file(&partial);
+            let result = create_at(source, &partial, strategy)
+                .and_then(|_| std::fs::rename(&partial, destination));
+            if result.is_err() {
+                let _ = std::fs::remove_file(&partial);
+            }
+            result
+        }
+    }
+}
+
+/// Link or clone the ebook at `path`, which must not exist yet
+fn create_at(source: &Path, path: &Path, strategy: &PlaceStrategy) -> io::Result<()> {
+    match strategy {
+        PlaceStrategy::Copy | PlaceStrategy::Move => transfer(source, path, strategy),
+        PlaceStrategy::Hardlink => std::fs::hard_link(source, path).map_err(|e| match e.kind() {
+            io::ErrorKind::CrossesDevices => io::Error::new(
+                e.kind(),
+                "Cannot hard link across filesystems, use the copy or symlink strategy instead",
+            ),
+            _ if is_unsupported(&e) => io::Error::new(
+                e.kind(),
+                format!("The filesystem does not support hard links: {e}"),
+            ),
+            _ => e,
+        }),
+        PlaceStrategy::Symlink => symlink(&source.canonicalize()?, path),
+        PlaceStrategy::RelativeSymlink => {
+            let source = source.canonicalize()?;
+            let parent = path
+                .parent()
+                .map(Path::canonicalize)
+                .transpose()?
+                .unwrap_or_default();
+            let target = pathdiff::diff_paths(&source, &parent).unwrap_or(source);
+            symlink(&target, path)
+        }
+        // Falls back to a regular copy when the filesystem can't clone, or the paths are on
+        // different filesystems
+        PlaceStrategy::Reflink => reflink_copy::reflink_or_copy(source, path).map(|_| ()),
+    }
+}
+
+/// A temporary name next to the destination, for a file which is not complete yet
+fn partial_path(destination: &Path) -> PathBuf {
+    let name = destination
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    destination.with_file_name(format!(".{name}.partial"))
+}
+
+/// Rename a file, falling back to a verified copy when the destination is on another filesystem
+pub fn move_file(source: &Path, destination: &Path) -> io::Result<()> {
+    match std::fs::rename(source, destination) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            move_across_devices(source, destination)
+        }
+        result => result,
+    }
+}
+
+/// Move a file to another filesystem, which can't be done with a rename. The file is copied
+/// next to its destination under a temporary name, flushed to disk and compared with the
+/// source before it takes the destination's name, and the source is only deleted once the copy
+/// is known to be complete.
+fn move_across_devices(source: &Path, destination: &Path) -> io::Result<()> {
+    let partial = partial_path(destination);
+
+    let copy = || -> io::Result<()> {
+        // Permissions are copied along with the contents, so the copy is opened read-only in
+        // case the ebook is. Its owner can still set its times and flush it.
+        std::fs::copy(source, &partial)?;
+        let file = File::open(&partial)?;
+        file.set_modified(source.metadata()?.modified()?)?;
+        file.sync_all()?;
+
+        if hash::hash_file(source)? != hash::hash_file(&partial)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "The copy of {} on the destination's filesystem does not match the original, \
+                     which was left in place",
+                    source.display()
+                ),
+            ));
+        }
+        std::fs::rename(&partial, destination)
+    };
+    if let Err(e) = copy() {
+        let _ = std::fs::remove_file(&partial);
+        return Err(e);
+    }
+
+    std::fs::remove_file(source)
+}
+
+fn symlink(target: &Path, destination: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    let result = std::os::unix::fs::symlink(target, destination);
+    #[cfg(windows)]
+    let result = std::os::windows::fs::symlink_file(target, destination);
+
+    result.map_err(|e| match e {
+        _ if is_unsupported(&e) => io::Error::new(
+            e.kind(),
+            format!("The filesystem does not support symbolic links: {e}"),
+        ),
+        e => e,
+    })
+}
+
+/// Whether an error means the filesystem doesn't support an operation at all. FAT and exFAT
+/// report links as not permitted rather than unsupported.
+fn is_unsupported(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Unsupported || (cfg!(unix) && e.raw_os_error() == Some(1))
+}
+
+/// Whether two paths lead to the same file, through a hard or symbolic link
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match (a.metadata(), b.metadata()) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+            _ => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        matches!((a.canonicalize(), b.canonicalize()), (Ok(a), Ok(b)) if a == b)
+    }
+}
+
+fn rename_alongside(
+    source: &Path,
+    destination: &Path,
+    strategy: &PlaceStrategy,
+) -> io::Result<Outcome> {
+    let alternative = free_alternative(destination);
+    transfer(source, &alternative, strategy)?;
+    Ok(Outcome::Renamed(alternative))
+}
+
+/// Find the first `name (N).ext` path next to `destination` which does not exist yet
+fn free_alternative(destination: &Path) -> PathBuf {
+    let (stem, extension) = split_name(destination);
+    (2..)
+        .map(|n| destination.with_file_name(format!("{stem} ({n}){extension}")))
+        .find(|candidate| !candidate.exists())
+        .expect("an unused file name always exists")
+}
+
+/// Whether `path` is one of the alternatives `Rename` would have picked for `destination`,
+/// such as `name (2).epub` for `name.epub`
+pub fn is_alternative(path: &Path, destination: &Path) -> bool {
+    if path.parent() != destination.parent() {
+        return false;
+    }
+    let (stem, extension) = split_name(destination);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.strip_prefix(&format!("{stem} ("))
+        .and_then(|rest| rest.strip_suffix(&format!("){extension}")))
+        .is_some_and(|n| n.parse::<u32>().is_ok_and(|n| n >= 2))
+}
+
+/// The stem of a file name and its extension, including the leading dot
+fn split_name(destination: &Path) -> (String, String) {
+    let stem = destination
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let extension = destination
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    (stem, extension)
+}
@@ -0,0 +1,289 @@
+use comfy_table::{modifiers::UTF8_ROUND_CORNERS, presets::UTF8_FULL, ContentArrangement, Table};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    authors::MultiAuthor,
+    duplicates::{DuplicateAction, DuplicateGroup},
+    error::{EbookSortError, Stage},
+    filename::{FieldSource, FieldSources},
+    metadata::BookMetadata,
+    place,
+};
+
+/// A single planned file operation: the ebook at `source` should end up at `destination`
+#[derive(Debug, Clone, Serialize)]
+pub struct Placement {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    /// The author credit the destination was built from, under the run's multi-author policy
+    pub author: Option<String>,
+    /// Whether each field the destination could be built from came from the ebook's metadata
+    /// or its file name
+    pub sources: FieldSources,
+    /// Whether this is an extra copy of a book, sent to the duplicates directory
+    pub duplicate: bool,
+}
+
+impl Placement {
+    /// Whether the ebook is already at its destination, or at an alternative of it picked
+    /// when resolving a conflict, typically because a previous run sorted it. As when placing,
+    /// a hard or symbolic link at the destination which leads to the ebook counts as in place.
+    pub fn is_in_place(&self) -> bool {
+        self.source == self.destination
+            || place::same_file(&self.source, &self.destination)
+            || place::is_alternative(&self.source, &self.destination)
+    }
+
+    /// The fields which were filled in from the file name, e.g. `title, author`
+    pub fn filename_fields(&self) -> String {
+        self.sources
+            .iter()
+            .filter(|(_, source)| **source == FieldSource::Filename)
+            .map(|(field, _)| field.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// A problem detected while planning which would prevent a placement from going through cleanly
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Conflict {
+    /// A file already exists at the destination
+    DestinationExists {
+        source: PathBuf,
+        destination: PathBuf,
+    },
+    /// More than one ebook would be placed at the same destination
+    DuplicateDestination {
+        destination: PathBuf,
+        sources: Vec<PathBuf>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct PlannedError<'a> {
+    stage: Stage,
+    path: &'a Path,
+    destination: Option<&'a Path>,
+    error: String,
+}
+
+/// How a single ebook was read while planning, for the run report
+#[derive(Debug, Clone, Default)]
+pub struct ScanInfo {
+    /// The metadata the placement was built from, unless the ebook could not be read
+    pub metadata: Option<BookMetadata>,
+    pub size: Option<u64>,
+    pub duration: Duration,
+}
+
+/// The full set of placements computed for a run, along with anything that went wrong
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// The policy used to credit books with more than one author
+    pub multi_author: MultiAuthor,
+    pub placements: Vec<Placement>,
+    /// Groups of ebooks which are copies of the same book, when duplicate detection is enabled
+    pub duplicates: Vec<DuplicateGroup>,
+    pub errors: Vec<EbookSortError>,
+    /// How every ebook was read, by path
+    pub scans: HashMap<PathBuf, ScanInfo>,
+}
+
+impl Plan {
+    /// Record the duplicate groups, dropping the placements of skipped copies and sending moved
+    /// copies to the duplicates directory instead
+    pub fn apply_duplicates(&mut self, groups: Vec<DuplicateGroup>) {
+        let actions = groups
+            .iter()
+            .flat_map(|group| &group.members)
+            .filter(|member| member.action != DuplicateAction::Place)
+            .map(|member| (member.path.as_path(), &member.action))
+            .collect::<HashMap<_, _>>();
+
+        self.placements
+            .retain_mut(|placement| match actions.get(placement.source.as_path()) {
+                Some(DuplicateAction::Skip) => false,
+                Some(DuplicateAction::MoveTo(destination)) => {
+                    placement.destination = destination.clone();
+                    placement.duplicate = true;
+                    true
+                }
+                _ => true,
+            });
+        self.duplicates = groups;
+    }
+
+    /// Detect placements which would collide with an existing file or with each other
+    pub fn conflicts(&self) -> Vec<Conflict> {
+        let mut conflicts = Vec::new();
+        let mut by_destination: HashMap<&Path, Vec<&Path>> = HashMap::new();
+
+        // Books which are already in place are left alone, so they can't collide
+        for placement in self.placements.iter().filter(|p| !p.is_in_place()) {
+            by_destination
+                .entry(&placement.destination)
+                .or_default()
+                .push(&placement.source);
+
+            if placement.destination.exists() {
+                conflicts.push(Conflict::DestinationExists {
+                    source: placement.source.clone(),
+                    destination: placement.destination.clone(),
+                });
+            }
+        }
+
+        let mut duplicates = by_destination
+            .into_iter()
+            .filter(|(_, sources)| sources.len() > 1)
+            .collect::<Vec<_>>();
+        duplicates.sort_by_key(|(destination, _)| *destination);
+        let duplicates =
+            duplicates
+                .into_iter()
+                .map(|(destination, sources)| Conflict::DuplicateDestination {
+                    destination: destination.to_path_buf(),
+                    sources: sources.into_iter().map(Path::to_path_buf).collect(),
+                });
+        conflicts.extend(duplicates);
+
+        conflicts
+    }
+
+    /// Render the plan as a table of source → destination rows, followed by any conflicts,
+    /// duplicates or errors
+    pub fn to_table(&self) -> Table {
+        let mut table = Table::new();
+        table
+            .load_preset(UTF8_FULL)
+            .apply_modifier(UTF8_ROUND_CORNERS)
+            .set_content_arrangement(ContentArrangement::Dynamic)
+            .set_header(vec![
+                "Status".to_string(),
+                "Source".to_string(),
+                format!("Author ({})", self.multi_author),
+                "Destination".to_string(),
+                "From Filename".to_string(),
+            ]);
+
+        for placement in &self.placements {
+            let status = if placement.is_in_place() {
+                "already sorted"
+            } else {
+                "planned"
+            };
+            table.add_row(vec![
+                status.to_string(),
+                placement.source.to_string_lossy().to_string(),
+                placement.author.clone().unwrap_or_default(),
+                placement.destination.to_string_lossy().to_string(),
+                placement.filename_fields(),
+            ]);
+        }
+
+        for conflict in self.conflicts() {
+            match conflict {
+                Conflict::DestinationExists {
+                    source,
+                    destination,
+                } => {
+                    table.add_row(vec![
+                        "conflict: destination exists".to_string(),
+                        source.to_string_lossy().to_string(),
+                        String::default(),
+                        destination.to_string_lossy().to_string(),
+                    ]);
+                }
+                Conflict::DuplicateDestination {
+                    destination,
+                    sources,
+                } => {
+                    let sources = sources
+                        .iter()
+                        .map(|s| s.to_string_lossy().to_string())
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    table.add_row(vec![
+                        "conflict: duplicate destination".to_string(),
+                        sources,
+                        String::default(),
+                        destination.to_string_lossy().to_string(),
+                    ]);
+                }
+            }
+        }
+
+        for group in &self.duplicates {
+            table.add_row(vec![
+                format!("duplicate: {}", group.kind),
+                group.members_cell(),
+                String::default(),
+                String::default(),
+            ]);
+        }
+
+        for error in &self.errors {
+            table.add_row(vec![
+                format!("error ({}): {}", error.stage(), error.message()),
+                error.path().to_string_lossy().to_string(),
+                String::default(),
+                error
+                    .destination()
+                    .map(|p| p.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+            ]);
+        }
+
+        table
+    }
+
+    /// Serialize the plan, including conflicts and errors, as pretty-printed JSON
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        #[derive(Serialize)]
+        struct JsonPlacement<'a> {
+            #[serde(flatten)]
+            placement: &'a Placement,
+            already_sorted: bool,
+        }
+
+        #[derive(Serialize)]
+        struct JsonPlan<'a> {
+            multi_author: MultiAuthor,
+            placements: Vec<JsonPlacement<'a>>,
+            conflicts: Vec<Conflict>,
+            duplicates: &'a [DuplicateGroup],
+            errors: Vec<PlannedError<'a>>,
+        }
+
+        serde_json::to_string_pretty(&JsonPlan {
+            multi_author: self.multi_author,
+            placements: self
+                .placements
+                .iter()
+                .map(|placement| JsonPlacement {
+                    placement,
+                    already_sorted: placement.is_in_place(),
+                })
+                .collect(),
+            conflicts: self.conflicts(),
+            duplicates: &self.duplicates,
+            errors: self
+                .errors
+                .iter()
+                .map(|e| PlannedError {
+                    stage: e.stage(),
+                    path: e.path(),
+                    destination: e.destination(),
+                    error: e.message(),
+                })
+                .collect(),
+        })
+    }
+}
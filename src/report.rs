@@ -0,0 +1,405 @@
+use clap::ValueEnum;
+use comfy_table::{modifiers::UTF8_ROUND_CORNERS, presets::UTF8_FULL, ContentArrangement, Table};
+use eyre::Result;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::{EbookSortError, Stage},
+    metadata::BookMetadata,
+    place::{Outcome, PlaceStrategy},
+    plan::ScanInfo,
+};
+
+/// The file format of the run report
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// A single JSON document with the run's totals and every file
+    #[default]
+    Json,
+    /// One row per file, followed by one row per total
+    Csv,
+    /// One JSON object per line for every file, followed by the run's totals
+    Ndjson,
+}
+
+/// What happened to a single file during the run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// The file would be placed, in a dry run
+    Planned,
+    Placed,
+    Overwritten,
+    Renamed,
+    /// A file already existed at the destination and was left untouched
+    Skipped,
+    /// A larger or newer file already existed at the destination
+    KeptExisting,
+    /// An identical file already existed at the destination
+    Identical,
+    AlreadySorted,
+    /// The file is an extra copy of another book and was left where it is
+    DuplicateSkipped,
+    Failed,
+}
+
+impl From<&Outcome> for Action {
+    fn from(outcome: &Outcome) -> Action {
+        match outcome {
+            Outcome::Placed => Action::Placed,
+            Outcome::AlreadyInPlace => Action::AlreadySorted,
+            Outcome::Skipped => Action::Skipped,
+            Outcome::Overwritten => Action::Overwritten,
+            Outcome::Renamed(_) => Action::Renamed,
+            Outcome::KeptExisting => Action::KeptExisting,
+            Outcome::Identical => Action::Identical,
+        }
+    }
+}
+
+/// The report entry for a single file
+#[derive(Debug, Serialize)]
+pub struct FileRecord {
+    pub source: PathBuf,
+    /// Where the file ended up, or would have ended up
+    pub destination: Option<PathBuf>,
+    pub action: Action,
+    /// The metadata the destination was built from, after author normalization
+    pub metadata: Option<BookMetadata>,
+    /// The file's size in bytes, unless it could not be read
+    pub size: Option<u64>,
+    /// The time spent reading and placing the file, in milliseconds
+    pub duration_ms: f64,
+    pub stage: Option<Stage>,
+    pub error: Option<String>,
+}
+
+/// The totals of a whole run
+#[derive(Debug, Serialize)]
+pub struct RunSummary<'a> {
+    pub root: &'a Path,
+    pub output: &'a Path,
+    pub strategy: &'a PlaceStrategy,
+    pub dry_run: bool,
+    /// When the run started, in seconds since the Unix epoch
+    pub started_at: u64,
+    pub elapsed_ms: f64,
+    pub files: usize,
+    /// The number of files for every action taken
+    pub actions: BTreeMap<Action, usize>,
+    pub errors: usize,
+}
+
+/// Everything that happened during a run, file by file
+pub struct Report {
+    started_at: SystemTime,
+    started: Instant,
+    records: Vec<FileRecord>,
+}
+
+impl Report {
+    pub fn start() -> Report {
+        Report {
+            started_at: SystemTime::now(),
+            started: Instant::now(),
+            records: Vec::new(),
+        }
+    }
+
+    /// Record a file which went through, along with how it was read and how long placing it took
+    pub fn add(
+        &mut self,
+        source: &Path,
+        destination: Option<&Path>,
+        action: Action,
+        scan: Option<&ScanInfo>,
+        placing: Duration,
+    ) {
+        self.records.push(FileRecord {
+            source: source.to_path_buf(),
+            destination: destination.map(Path::to_path_buf),
+            action,
+            metadata: scan.and_then(|scan| scan.metadata.clone()),
+            size: scan.and_then(|scan| scan.size),
+            duration_ms: milliseconds(scan.map(|scan| scan.duration).unwrap_or_default() + placing),
+            stage: None,
+            error: None,
+        });
+    }
+
+    /// Record a file which failed
+    pub fn add_error(&mut self, error: &EbookSortError, scan: Option<&ScanInfo>) {
+        self.records.push(FileRecord {
+            source: error.path().to_path_buf(),
+            destination: error.destination().map(Path::to_path_buf),
+            action: Action::Failed,
+            metadata: scan.and_then(|scan| scan.metadata.clone()),
+            size: scan.and_then(|scan| scan.size),
+            duration_ms: milliseconds(scan.map(|scan| scan.duration).unwrap_or_default()),
+            stage: Some(error.stage()),
+            error: Some(error.message()),
+        });
+    }
+
+    pub fn summary<'a>(
+        &self,
+        root: &'a Path,
+        output: &'a Path,
+        strategy: &'a PlaceStrategy,
+        dry_run: bool,
+    ) -> RunSummary<'a> {
+        let mut actions = BTreeMap::new();
+        for record in &self.records {
+            *actions.entry(record.action).or_default() += 1;
+        }
+        RunSummary {
+            root,
+            output,
+            strategy,
+            dry_run,
+            started_at: self
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            elapsed_ms: milliseconds(self.started.elapsed()),
+            files: self.records.len(),
+            errors: actions.get(&Action::Failed).copied().unwrap_or_default(),
+            actions,
+        }
+    }
+
+    /// The run's statistics, for the end of a run. `authors_created` counts the directories
+    /// created directly in the output directory, which hold authors with the default template.
+    pub fn to_table(
+        &self,
+        strategy: &PlaceStrategy,
+        duplicate_groups: usize,
+        authors_created: usize,
+    ) -> Table {
+        let count = |actions: &[Action]| {
+            self.records
+                .iter()
+                .filter(|record| actions.contains(&record.action))
+                .count()
+        };
+        let placed = [Action::Placed, Action::Overwritten, Action::Renamed];
+        let bytes = self
+            .records
+            .iter()
+            .filter(|record| placed.contains(&record.action))
+            .filter_map(|record| record.size)
+            .sum::<u64>();
+        let verb = match strategy {
+            PlaceStrategy::Move => "Moved",
+            PlaceStrategy::Copy => "Copied",
+            PlaceStrategy::Hardlink | PlaceStrategy::Symlink | PlaceStrategy::RelativeSymlink => {
+                "Linked"
+            }
+            PlaceStrategy::Reflink => "Cloned",
+        };
+
+        let mut table = Table::new();
+        table
+            .load_preset(UTF8_FULL)
+            .apply_modifier(UTF8_ROUND_CORNERS)
+            .set_content_arrangement(ContentArrangement::Dynamic)
+            .set_header(vec!["Summary", ""]);
+
+        let mut rows = vec![
+            ("Files scanned".to_string(), self.records.len().to_string()),
+            (verb.to_string(), count(&placed).to_string()),
+            (
+                "Skipped".to_string(),
+                count(&[Action::Skipped, Action::KeptExisting, Action::Identical]).to_string(),
+            ),
+            (
+                "Already sorted".to_string(),
+                count(&[Action::AlreadySorted]).to_string(),
+            ),
+            ("Duplicate groups".to_string(), duplicate_groups.to_string()),
+            (
+                "Duplicates left in place".to_string(),
+                count(&[Action::DuplicateSkipped]).to_string(),
+            ),
+        ];
+        let mut errors = BTreeMap::new();
+        for record in &self.records {
+            if let Some(stage) = record.stage {
+                *errors.entry(stage).or_insert(0) += 1;
+            }
+        }
+        rows.push((
+            "Errors".to_string(),
+            errors.values().sum::<usize>().to_string(),
+        ));
+        rows.extend(
+            errors
+                .into_iter()
+                .map(|(stage, count)| (format!("  during {stage}"), count.to_string())),
+        );
+        rows.extend([
+            ("Bytes transferred".to_string(), format_bytes(bytes)),
+            ("Authors created".to_string(), authors_created.to_string()),
+            (
+                "Elapsed".to_string(),
+                format!("{:.2?}", self.started.elapsed()),
+            ),
+        ]);
+
+        for (name, value) in rows {
+            table.add_row(vec![name, value]);
+        }
+        table
+    }
+
+    /// Write the report, with files in path order so that reports of different runs can be
+    /// compared line by line
+    pub fn write(&mut self, path: &Path, format: ReportFormat, summary: &RunSummary) -> Result<()> {
+        self.records.sort_by(|a, b| a.source.cmp(&b.source));
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        match format {
+            ReportFormat::Json => {
+                #[derive(Serialize)]
+                struct JsonReport<'a> {
+                    run: &'a RunSummary<'a>,
+                    files: &'a [FileRecord],
+                }
+                serde_json::to_writer_pretty(
+                    &mut writer,
+                    &JsonReport {
+                        run: summary,
+                        files: &self.records,
+                    },
+                )?;
+                writeln!(writer)?;
+            }
+            ReportFormat::Ndjson => {
+                #[derive(Serialize)]
+                #[serde(tag = "type", rename_all = "snake_case")]
+                enum Line<'a> {
+                    File(&'a FileRecord),
+                    Run(&'a RunSummary<'a>),
+                }
+                for record in &self.records {
+                    serde_json::to_writer(&mut writer, &Line::File(record))?;
+                    writeln!(writer)?;
+                }
+                serde_json::to_writer(&mut writer, &Line::Run(summary))?;
+                writeln!(writer)?;
+            }
+            ReportFormat::Csv => write_csv(writer, &self.records, summary)?,
+        }
+        Ok(())
+    }
+}
+
+/// A CSV row, either for a single file or for one of the run's totals
+#[derive(Serialize, Default)]
+struct CsvRow<'a> {
+    record: &'static str,
+    source: Option<&'a Path>,
+    destination: Option<&'a Path>,
+    action: Option<String>,
+    title: Option<&'a str>,
+    authors: Option<String>,
+    series: Option<&'a str>,
+    series_index: Option<f64>,
+    isbn: Option<&'a str>,
+    published: Option<&'a str>,
+    duration_ms: Option<f64>,
+    stage: Option<Stage>,
+    error: Option<&'a str>,
+    count: Option<usize>,
+}
+
+fn write_csv(writer: impl Write, records: &[FileRecord], summary: &RunSummary) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+
+    for record in records {
+        let metadata = record.metadata.as_ref();
+        writer.serialize(CsvRow {
+            record: "file",
+            source: Some(&record.source),
+            destination: record.destination.as_deref(),
+            action: Some(name(&record.action)),
+            title: metadata.and_then(BookMetadata::title),
+            authors: metadata.map(|m| {
+                m.authors
+                    .iter()
+                    .map(|author| author.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            }),
+            series: metadata.and_then(|m| m.series.as_deref()),
+            series_index: metadata.and_then(|m| m.series_index),
+            isbn: metadata
+                .and_then(BookMetadata::isbn)
+                .map(|isbn| isbn.value.as_str()),
+            published: metadata.and_then(|m| m.published.as_deref()),
+            duration_ms: Some(record.duration_ms),
+            stage: record.stage,
+            error: record.error.as_deref(),
+            count: None,
+        })?;
+    }
+
+    let totals = summary
+        .actions
+        .iter()
+        .map(|(action, count)| (name(action), *count))
+        .chain([("files".to_string(), summary.files)]);
+    for (action, count) in totals {
+        writer.serialize(CsvRow {
+            record: "total",
+            action: Some(action),
+            count: Some(count),
+            ..Default::default()
+        })?;
+    }
+    writer.serialize(CsvRow {
+        record: "total",
+        action: Some("elapsed".to_string()),
+        duration_ms: Some(summary.elapsed_ms),
+        ..Default::default()
+    })?;
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// The name an action is given in every report format
+fn name(action: &Action) -> String {
+    serde_json::to_value(action)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// A size in bytes, in the largest binary unit it fills, e.g. `1.5 MiB`
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{bytes} B"),
+        _ => format!("{size:.1} {}", UNITS[unit]),
+    }
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    // Rounded to the microsecond, which keeps float noise out of the reports
+    duration.as_micros() as f64 / 1000.0
+}
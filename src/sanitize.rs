@@ -0,0 +1,210 @@
+use clap::ValueEnum;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// The set of filesystem rules generated path components must satisfy
+#[derive(ValueEnum, Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum SanitizeProfile {
+    /// Only replace `/`, which no POSIX filesystem allows in a file name
+    #[default]
+    Posix,
+    /// Additionally replace characters and names reserved on Windows, NTFS and SMB shares
+    Windows,
+    /// Windows rules, also trimming leading spaces and dots which FAT32 and exFAT reject
+    Fat32,
+    /// FAT32 rules, also transliterating every component to plain ASCII
+    Ascii,
+}
+
+/// The character used in place of anything the profile does not allow
+const REPLACEMENT: char = '_';
+
+const WINDOWS_RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+impl SanitizeProfile {
+    fn is_windows_like(self) -> bool {
+        !matches!(self, SanitizeProfile::Posix)
+    }
+
+    fn is_reserved_char(self, c: char) -> bool {
+        c == '/' || (self.is_windows_like() && WINDOWS_RESERVED_CHARS.contains(&c))
+    }
+}
+
+/// Make a single generated path component safe to use under `profile`.
+///
+/// The component is normalized to NFC, stripped of control characters, has reserved characters
+/// replaced and is truncated to at most `max_bytes` bytes. When `is_file` is set, the extension
+/// is kept intact while truncating.
+pub fn sanitize_component(
+    component: &str,
+    profile: SanitizeProfile,
+    max_bytes: usize,
+    is_file: bool,
+) -> String {
+    let normalized = match profile {
+        SanitizeProfile::Ascii => transliterate(component),
+        _ => component.nfc().collect(),
+    };
+
+    let mut sanitized = normalized
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| {
+            if profile.is_reserved_char(c) {
+                REPLACEMENT
+            } else {
+                c
+            }
+        })
+        .collect::<String>();
+
+    if profile.is_windows_like() {
+        sanitized = sanitized.trim_end_matches(['.', ' ']).to_string();
+    }
+    if matches!(profile, SanitizeProfile::Fat32 | SanitizeProfile::Ascii) {
+        sanitized = sanitized.trim_start_matches(['.', ' ']).to_string();
+    }
+
+    let (stem, extension) = match sanitized.rsplit_once('.') {
+        Some((stem, extension)) if is_file && !stem.is_empty() => {
+            (stem.to_string(), format!(".{extension}"))
+        }
+        _ => (sanitized.clone(), String::new()),
+    };
+
+    let mut stem = stem.trim().to_string();
+    if profile.is_windows_like()
+        && WINDOWS_RESERVED_NAMES.iter().any(|reserved| {
+            stem.split('.')
+                .next()
+                .unwrap_or_default()
+                .eq_ignore_ascii_case(reserved)
+        })
+    {
+        stem.insert(0, REPLACEMENT);
+    }
+    if stem.is_empty() || stem == "." || stem == ".." {
+        stem = REPLACEMENT.to_string();
+    }
+
+    let stem = truncate_bytes(&stem, max_bytes.saturating_sub(extension.len()).max(1));
+    let stem = if profile.is_windows_like() {
+        stem.trim_end_matches(['.', ' '])
+    } else {
+        stem
+    };
+
+    format!("{}{extension}", stem.trim_end())
+}
+
+/// Truncate a string to at most `max_bytes` bytes without splitting a character
+fn truncate_bytes(value: &str, max_bytes: usize) -> &str {
+    if value.len() <= max_bytes {
+        return value;
+    }
+    let mut end = max_bytes;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+/// Reduce a string to ASCII, decomposing accented letters and replacing anything left over
+fn transliterate(value: &str) -> String {
+    let mut ascii = String::with_capacity(value.len());
+    for c in value.nfkd().filter(|c| !is_combining_mark(*c)) {
+        match c {
+            c if c.is_ascii() => ascii.push(c),
+            'Æ' => ascii.push_str("AE"),
+            'æ' => ascii.push_str("ae"),
+            'Œ' => ascii.push_str("OE"),
+            'œ' => ascii.push_str("oe"),
+            'ß' => ascii.push_str("ss"),
+            'Ø' => ascii.push('O'),
+            'ø' => ascii.push('o'),
+            'Ł' => ascii.push('L'),
+            'ł' => ascii.push('l'),
+            'Đ' | 'Ð' => ascii.push('D'),
+            'đ' | 'ð' => ascii.push('d'),
+            'Þ' => ascii.push_str("Th"),
+            'þ' => ascii.push_str("th"),
+            '‘' | '’' => ascii.push('\''),
+            '“' | '”' => ascii.push('"'),
+            '–' | '—' => ascii.push('-'),
+            '…' => ascii.push_str("..."),
+            _ => ascii.push(REPLACEMENT),
+        }
+    }
+    ascii
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use SanitizeProfile::*;
+
+    fn file(name: &str, profile: SanitizeProfile) -> String {
+        sanitize_component(name, profile, 255, true)
+    }
+
+    fn dir(name: &str, profile: SanitizeProfile) -> String {
+        sanitize_component(name, profile, 255, false)
+    }
+
+    #[test]
+    fn replaces_reserved_characters() {
+        assert_eq!(dir("AC/DC: Live?", Posix), "AC_DC: Live?");
+        assert_eq!(dir("AC/DC: Live?", Windows), "AC_DC_ Live_");
+        assert_eq!(dir("Bell\u{7}s", Posix), "Bells");
+    }
+
+    #[test]
+    fn normalizes_and_transliterates() {
+        assert_eq!(dir("Poke\u{301}mon", Posix), "Pok\u{e9}mon");
+        assert_eq!(dir("Pokémon Æon", Ascii), "Pokemon AEon");
+        assert_eq!(dir("北京", Ascii), "__");
+    }
+
+    #[test]
+    fn prefixes_reserved_names() {
+        assert_eq!(file("CON.epub", Windows), "_CON.epub");
+        assert_eq!(dir("aux", Fat32), "_aux");
+        assert_eq!(file("CON.epub", Posix), "CON.epub");
+        assert_eq!(file("Conan.epub", Windows), "Conan.epub");
+    }
+
+    #[test]
+    fn trims_dots_and_spaces() {
+        assert_eq!(dir("Dune. ", Posix), "Dune.");
+        assert_eq!(dir("Dune. ", Windows), "Dune");
+        assert_eq!(dir(". hidden", Windows), ". hidden");
+        assert_eq!(dir(". hidden", Fat32), "hidden");
+    }
+
+    #[test]
+    fn replaces_dot_components() {
+        assert_eq!(dir("..", Posix), "_");
+        assert_eq!(dir(".", Posix), "_");
+        assert_eq!(dir("...", Windows), "_");
+    }
+
+    #[test]
+    fn caps_the_length_in_bytes() {
+        assert_eq!(sanitize_component("abcdefghij", Posix, 5, false), "abcde");
+        // The extension is kept whole, and only the stem is shortened
+        assert_eq!(
+            sanitize_component("abcdefghij.epub", Posix, 10, true),
+            "abcde.epub"
+        );
+        // `é` takes two bytes, so the third one doesn't fit in five
+        assert_eq!(sanitize_component("ééé", Posix, 5, false), "éé");
+        assert_eq!(sanitize_component("éé.epub", Posix, 8, true), "é.epub");
+        // Truncating may leave a trailing dot, which Windows doesn't allow
+        assert_eq!(sanitize_component("Vol. 2", Windows, 4, false), "Vol");
+    }
+}
@@ -0,0 +1,658 @@
+use std::{fmt, str::FromStr};
+
+use crate::{
+    authors::{self, AuthorPolicy, NameForm},
+    metadata::{BookMetadata, Contributor},
+};
+
+/// A metadata value which can be referenced from a path template
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Title,
+    Author,
+    /// The authors in sort order, e.g. `Tolkien, J. R. R.`, whichever form `{author}` uses
+    AuthorSort,
+    Contributor,
+    Language,
+    Identifier,
+    Publisher,
+    Date,
+    Year,
+    Subject,
+    Description,
+    Rights,
+    Source,
+    Type,
+    Format,
+    Coverage,
+    Relation,
+    Series,
+    SeriesIndex,
+    /// The extension of the original file, e.g. `epub`
+    Ext,
+    /// The name of the original file, without its extension
+    Stem,
+}
+
+impl Field {
+    const ALL: &'static [(&'static str, Field)] = &[
+        ("title", Field::Title),
+        ("author", Field::Author),
+        ("creator", Field::Author),
+        ("author_sort", Field::AuthorSort),
+        ("contributor", Field::Contributor),
+        ("language", Field::Language),
+        ("identifier", Field::Identifier),
+        ("publisher", Field::Publisher),
+        ("date", Field::Date),
+        ("year", Field::Year),
+        ("subject", Field::Subject),
+        ("description", Field::Description),
+        ("rights", Field::Rights),
+        ("source", Field::Source),
+        ("type", Field::Type),
+        ("format", Field::Format),
+        ("coverage", Field::Coverage),
+        ("relation", Field::Relation),
+        ("series", Field::Series),
+        ("series_index", Field::SeriesIndex),
+        ("ext", Field::Ext),
+        ("stem", Field::Stem),
+    ];
+
+    fn from_name(name: &str) -> Option<Field> {
+        Field::ALL
+            .iter()
+            .find(|(candidate, _)| *candidate == name)
+            .map(|(_, field)| *field)
+    }
+
+    fn names() -> String {
+        Field::ALL
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// A transformation applied to a placeholder's value after it has been resolved
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Filter {
+    Upper,
+    Lower,
+    TitleCase,
+    Trim,
+    Initial,
+    Truncate(usize),
+}
+
+impl Filter {
+    fn parse(name: &str) -> Result<Option<Filter>, TemplateError> {
+        let filter = match name {
+            "upper" => Filter::Upper,
+            "lower" => Filter::Lower,
+            "titlecase" => Filter::TitleCase,
+            "trim" => Filter::Trim,
+            "initial" => Filter::Initial,
+            _ => {
+                let Some(arg) = name
+                    .strip_prefix("trunc(")
+                    .and_then(|rest| rest.strip_suffix(')'))
+                else {
+                    return Ok(None);
+                };
+                let len = arg
+                    .trim()
+                    .parse()
+                    .map_err(|_| TemplateError::InvalidFilter(name.to_string()))?;
+                Filter::Truncate(len)
+            }
+        };
+        Ok(Some(filter))
+    }
+
+    fn apply(&self, value: String) -> String {
+        match self {
+            Filter::Upper => value.to_uppercase(),
+            Filter::Lower => value.to_lowercase(),
+            Filter::TitleCase => value
+                .split(' ')
+                .map(|word| {
+                    let mut chars = word.chars();
+                    match chars.next() {
+                        Some(first) => first.to_uppercase().chain(chars).collect(),
+                        None => String::new(),
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" "),
+            Filter::Trim => value.trim().to_string(),
+            Filter::Initial => value
+                .trim()
+                .chars()
+                .next()
+                .map(|c| c.to_uppercase().collect())
+                .unwrap_or_default(),
+            Filter::Truncate(len) => value
+                .chars()
+                .take(*len)
+                .collect::<String>()
+                .trim_end()
+                .to_string(),
+        }
+    }
+}
+
+/// One alternative in a placeholder's fallback chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Alternative {
+    Field(Field),
+    Literal(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Placeholder {
+    alternatives: Vec<Alternative>,
+    filters: Vec<Filter>,
+    /// Minimum width of the integer part of a numeric value, padded with zeros
+    pad: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Placeholder(Placeholder),
+    /// A section which is only rendered when every placeholder inside it has a value
+    Optional(Vec<Segment>),
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum TemplateError {
+    #[error("The template is empty")]
+    Empty,
+    #[error("The template contains an empty path component")]
+    EmptyComponent,
+    #[error("Unterminated placeholder starting at position {0}")]
+    Unterminated(usize),
+    #[error("Unexpected '}}' at position {0}, use '}}}}' for a literal brace")]
+    UnexpectedBrace(usize),
+    #[error("Empty placeholder at position {0}")]
+    EmptyPlaceholder(usize),
+    #[error("Unknown placeholder '{0}', expected one of: {names}", names = Field::names())]
+    UnknownPlaceholder(String),
+    #[error("Invalid filter '{0}'")]
+    InvalidFilter(String),
+    #[error("Invalid format spec '{0}', expected a width such as '02'")]
+    InvalidFormatSpec(String),
+    #[error("Fallback '{0}' appears after a filter, fallbacks must come first")]
+    FallbackAfterFilter(String),
+    #[error("Unterminated optional section starting at position {0}")]
+    UnterminatedOptional(usize),
+    #[error("Unexpected '>' at position {0}, use '>>' for a literal '>'")]
+    UnexpectedOptionalEnd(usize),
+    #[error("Optional sections cannot be nested, found '<' at position {0}")]
+    NestedOptional(usize),
+    #[error("Optional sections cannot contain '/', found at position {0}")]
+    SeparatorInOptional(usize),
+    #[error("The optional section starting at position {0} contains no placeholders")]
+    EmptyOptional(usize),
+}
+
+/// A path template such as `{author}/{series|"Standalone"}/{series_index:02} - {title}.{ext}`.
+///
+/// Each `/`-separated component of the template renders to a single path component.
+/// Placeholders may list fallbacks separated by `|`, either other fields or quoted literals,
+/// followed by filters (`upper`, `lower`, `titlecase`, `trim`, `initial`, `trunc(N)`) and an
+/// optional `:0N` spec which zero-pads numeric values. Text wrapped in `<...>` is only rendered
+/// when every placeholder inside it has a value, e.g. `<{series_index:02} - >{title}`, and path
+/// components which render empty are dropped entirely.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    source: String,
+    components: Vec<Vec<Segment>>,
+}
+
+impl Template {
+    pub const DEFAULT: &'static str =
+        "{author|\"Unsorted\"}/{series}/<{series_index:02} - >{title|stem}.{ext}";
+
+    pub fn parse(template: &str) -> Result<Template, TemplateError> {
+        if template.trim().is_empty() {
+            return Err(TemplateError::Empty);
+        }
+
+        let mut components = Vec::new();
+        let mut segments = Vec::new();
+        let mut optional: Option<(usize, Vec<Segment>)> = None;
+        let mut literal = String::new();
+        let mut chars = template.char_indices().peekable();
+
+        while let Some((position, c)) = chars.next() {
+            let next = chars.peek().map(|(_, c)| *c);
+            match c {
+                '{' | '}' | '<' | '>' if next == Some(c) => {
+                    chars.next();
+                    literal.push(c);
+                }
+                '}' => return Err(TemplateError::UnexpectedBrace(position)),
+                '<' => {
+                    if optional.is_some() {
+                        return Err(TemplateError::NestedOptional(position));
+                    }
+                    push_literal(&mut segments, &mut literal);
+                    optional = Some((position, Vec::new()));
+                }
+                '>' => {
+                    let Some((start, mut inner)) = optional.take() else {
+                        return Err(TemplateError::UnexpectedOptionalEnd(position));
+                    };
+                    push_literal(&mut inner, &mut literal);
+                    if !inner
+                        .iter()
+                        .any(|segment| matches!(segment, Segment::Placeholder(_)))
+                    {
+                        return Err(TemplateError::EmptyOptional(start));
+                    }
+                    segments.push(Segment::Optional(inner));
+                }
+                '{' => {
+                    let mut body = String::new();
+                    let mut in_quotes = false;
+                    let mut closed = false;
+                    for (_, c) in chars.by_ref() {
+                        match c {
+                            '"' => {
+                                in_quotes = !in_quotes;
+                                body.push(c);
+                            }
+                            '}' if !in_quotes => {
+                                closed = true;
+                                break;
+                            }
+                            _ => body.push(c),
+                        }
+                    }
+                    if !closed {
+                        return Err(TemplateError::Unterminated(position));
+                    }
+                    let target = match optional.as_mut() {
+                        Some((_, inner)) => inner,
+                        None => &mut segments,
+                    };
+                    push_literal(target, &mut literal);
+                    target.push(Segment::Placeholder(parse_placeholder(&body, position)?));
+                }
+                '/' => {
+                    if optional.is_some() {
+                        return Err(TemplateError::SeparatorInOptional(position));
+                    }
+                    push_literal(&mut segments, &mut literal);
+                    if segments.is_empty() {
+                        return Err(TemplateError::EmptyComponent);
+                    }
+                    components.push(std::mem::take(&mut segments));
+                }
+                _ => literal.push(c),
+            }
+        }
+
+        if let Some((start, _)) = optional {
+            return Err(TemplateError::UnterminatedOptional(start));
+        }
+        push_literal(&mut segments, &mut literal);
+        if segments.is_empty() {
+            return Err(TemplateError::EmptyComponent);
+        }
+        components.push(segments);
+
+        Ok(Template {
+            source: template.to_string(),
+            components,
+        })
+    }
+
+    /// Render the template into a list of path components, one per `/`-separated part of
+    /// the template. Components which render to an empty string are dropped.
+    pub fn render(&self, context: &RenderContext) -> Vec<String> {
+        self.components
+            .iter()
+            .filter_map(|segments| render_segments(segments, context, false))
+            .filter(|component| !component.trim().is_empty())
+            .collect()
+    }
+}
+
+/// Render a run of segments. When `optional` is set, `None` is returned if any placeholder
+/// rendered empty, so that the whole section can be left out.
+fn render_segments(
+    segments: &[Segment],
+    context: &RenderContext,
+    optional: bool,
+) -> Option<String> {
+    let mut rendered = String::new();
+    for segment in segments {
+        match segment {
+            Segment::Literal(literal) => rendered.push_str(literal),
+            Segment::Placeholder(placeholder) => {
+                let value = placeholder.render(context);
+                if optional && value.trim().is_empty() {
+                    return None;
+                }
+                rendered.push_str(&value);
+            }
+            Segment::Optional(inner) => {
+                if let Some(value) = render_segments(inner, context, true) {
+                    rendered.push_str(&value);
+                }
+            }
+        }
+    }
+    Some(rendered)
+}
+
+fn push_literal(segments: &mut Vec<Segment>, literal: &mut String) {
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(std::mem::take(literal)));
+    }
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        Template::parse(Template::DEFAULT).expect("the default template is valid")
+    }
+}
+
+impl FromStr for Template {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Template::parse(s)
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn parse_placeholder(body: &str, position: usize) -> Result<Placeholder, TemplateError> {
+    let parts = split_unquoted(body, '|');
+    let (last, rest) = parts.split_last().expect("split always yields one part");
+
+    // The format spec can only follow the last part, and never inside a quoted literal
+    let (last, pad) = match split_unquoted(last, ':').as_slice() {
+        [value] => (value.clone(), None),
+        [value, spec] => {
+            let spec = spec.trim();
+            let width = spec
+                .parse::<usize>()
+                .ok()
+                .filter(|_| !spec.is_empty() && spec.chars().all(|c| c.is_ascii_digit()))
+                .ok_or_else(|| TemplateError::InvalidFormatSpec(spec.to_string()))?;
+            (value.clone(), Some(width))
+        }
+        _ => return Err(TemplateError::InvalidFormatSpec(last.clone())),
+    };
+
+    let mut alternatives = Vec::new();
+    let mut filters = Vec::new();
+
+    for part in rest.iter().chain(std::iter::once(&last)) {
+        let part = part.trim();
+        if part.is_empty() {
+            return Err(TemplateError::EmptyPlaceholder(position));
+        }
+
+        if let Some(literal) = part
+            .strip_prefix('"')
+            .and_then(|rest| rest.strip_suffix('"'))
+        {
+            if !filters.is_empty() {
+                return Err(TemplateError::FallbackAfterFilter(part.to_string()));
+            }
+            alternatives.push(Alternative::Literal(literal.to_string()));
+        } else if let Some(field) = Field::from_name(part) {
+            if !filters.is_empty() {
+                return Err(TemplateError::FallbackAfterFilter(part.to_string()));
+            }
+            alternatives.push(Alternative::Field(field));
+        } else if alternatives.is_empty() {
+            return Err(TemplateError::UnknownPlaceholder(part.to_string()));
+        } else if let Some(filter) = Filter::parse(part)? {
+            filters.push(filter);
+        } else {
+            return Err(TemplateError::UnknownPlaceholder(part.to_string()));
+        }
+    }
+
+    Ok(Placeholder {
+        alternatives,
+        filters,
+        pad,
+    })
+}
+
+/// Split `input` on `separator`, ignoring separators inside double quotes
+fn split_unquoted(input: &str, separator: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut in_quotes = false;
+    for c in input.chars() {
+        if c == '"' {
+            in_quotes = !in_quotes;
+        }
+        if c == separator && !in_quotes {
+            parts.push(String::new());
+        } else {
+            parts.last_mut().expect("parts is never empty").push(c);
+        }
+    }
+    parts
+}
+
+impl Placeholder {
+    fn render(&self, context: &RenderContext) -> String {
+        let value = self
+            .alternatives
+            .iter()
+            .find_map(|alternative| match alternative {
+                Alternative::Field(field) => context.field(*field),
+                Alternative::Literal(literal) => Some(literal.clone()),
+            })
+            .unwrap_or_default();
+
+        let value = match self.pad {
+            Some(width) => pad_number(&value, width),
+            None => value,
+        };
+
+        self.filters
+            .iter()
+            .fold(value, |value, filter| filter.apply(value))
+    }
+}
+
+/// Zero-pad the integer part of a numeric value, leaving any fractional part as-is
+/// (e.g. `2.5` with a width of 2 becomes `02.5`). Non-numeric values are returned unchanged.
+fn pad_number(value: &str, width: usize) -> String {
+    let value = value.trim();
+    let (integer, fraction) = match value.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (value, None),
+    };
+    if integer.is_empty() || !integer.chars().all(|c| c.is_ascii_digit()) {
+        return value.to_string();
+    }
+    match fraction {
+        Some(fraction) => format!("{integer:0>width$}.{fraction}"),
+        None => format!("{integer:0>width$}"),
+    }
+}
+
+/// The values available to a template when rendering the destination of a single ebook
+pub struct RenderContext<'a> {
+    pub metadata: &'a BookMetadata,
+    /// How `{author}` credits the book's authors
+    pub authors: AuthorPolicy,
+    pub extension: String,
+    pub stem: String,
+}
+
+impl RenderContext<'_> {
+    /// The author credit rendered by `{author}`
+    pub fn author(&self) -> Option<String> {
+        self.authors
+            .credit(&self.metadata.authors, self.authors.form)
+    }
+
+    fn field(&self, field: Field) -> Option<String> {
+        let metadata = self.metadata;
+        let value = match field {
+            Field::Title => metadata.title().map(str::to_string),
+            Field::Author => self.author(),
+            Field::AuthorSort => self.authors.credit(&metadata.authors, NameForm::Sort),
+            Field::Contributor => Some(join_names(&metadata.contributors, self.authors.form)),
+            Field::Language => metadata.languages.first().cloned(),
+            Field::Identifier => metadata
+                .isbn()
+                .or(metadata.identifiers.first())
+                .map(|id| id.value.clone()),
+            Field::Publisher => metadata.publisher.clone(),
+            Field::Date => metadata.published.clone(),
+            Field::Year => metadata.year().map(str::to_string),
+            Field::Subject => Some(metadata.subjects.join(", ")),
+            Field::Description => metadata.description.clone(),
+            Field::Rights => metadata.other("rights").map(str::to_string),
+            Field::Source => metadata.other("source").map(str::to_string),
+            Field::Type => metadata.other("type").map(str::to_string),
+            Field::Format => metadata.other("format").map(str::to_string),
+            Field::Coverage => metadata.other("coverage").map(str::to_string),
+            Field::Relation => metadata.other("relation").map(str::to_string),
+            Field::Series => metadata.series.clone(),
+            Field::SeriesIndex => metadata.series_index.map(|index| index.to_string()),
+            Field::Ext => Some(self.extension.clone()),
+            Field::Stem => Some(self.stem.clone()),
+        };
+        value
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    }
+}
+
+fn join_names(contributors: &[Contributor], form: NameForm) -> String {
+    contributors
+        .iter()
+        .map(|c| c.name_in(form))
+        .collect::<Vec<_>>()
+        .join(authors::separator(form))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dune(series: bool) -> BookMetadata {
+        BookMetadata {
+            titles: vec!["Dune".to_string()],
+            authors: vec![Contributor::new("Frank Herbert")],
+            series: series.then(|| "Dune Chronicles".to_string()),
+            series_index: series.then_some(1.0),
+            ..Default::default()
+        }
+    }
+
+    fn render(template: &str, metadata: &BookMetadata) -> Vec<String> {
+        let context = RenderContext {
+            metadata,
+            authors: AuthorPolicy {
+                max_authors: 3,
+                ..Default::default()
+            },
+            extension: "epub".to_string(),
+            stem: "dune-original".to_string(),
+        };
+        Template::parse(template).unwrap().render(&context)
+    }
+
+    #[test]
+    fn renders_the_default_template() {
+        assert_eq!(
+            render(Template::DEFAULT, &dune(true)),
+            ["Frank Herbert", "Dune Chronicles", "01 - Dune.epub"]
+        );
+        // The series folder and the optional index are left out
+        assert_eq!(
+            render(Template::DEFAULT, &dune(false)),
+            ["Frank Herbert", "Dune.epub"]
+        );
+        assert_eq!(
+            render(Template::DEFAULT, &BookMetadata::default()),
+            ["Unsorted", "dune-original.epub"]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_first_value_present() {
+        let book = dune(false);
+        assert_eq!(render("{series|title}", &book), ["Dune"]);
+        assert_eq!(render("{series|publisher|\"None\"}", &book), ["None"]);
+        // Separators inside quotes are part of the literal
+        assert_eq!(render("{series|\"A|B:C\"}", &book), ["A|B:C"]);
+        assert_eq!(render("{publisher}", &book), Vec::<String>::new());
+    }
+
+    #[test]
+    fn applies_filters_and_padding() {
+        let book = dune(true);
+        assert_eq!(
+            render("{author|initial}/{title|upper}", &book),
+            ["F", "DUNE"]
+        );
+        assert_eq!(render("{series|trunc(5)}", &book), ["Dune"]);
+        assert_eq!(render("{series_index:03}", &book), ["001"]);
+        assert_eq!(
+            render("{{{title}}} <<{series_index}>>", &book),
+            ["{Dune} <1>"]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        let error = |template| Template::parse(template).unwrap_err();
+        assert_eq!(
+            error("{title|upper|stem}"),
+            TemplateError::FallbackAfterFilter("stem".to_string())
+        );
+        assert_eq!(
+            error("<{series} <{title}>"),
+            TemplateError::NestedOptional(10)
+        );
+        assert_eq!(
+            error("<{series}/>{title}"),
+            TemplateError::SeparatorInOptional(9)
+        );
+        assert_eq!(
+            error("{autor}"),
+            TemplateError::UnknownPlaceholder("autor".to_string())
+        );
+        assert_eq!(
+            error("{title|shout}"),
+            TemplateError::UnknownPlaceholder("shout".to_string())
+        );
+        assert_eq!(
+            error("{title:x2}"),
+            TemplateError::InvalidFormatSpec("x2".to_string())
+        );
+        assert_eq!(error("{author}//{title}"), TemplateError::EmptyComponent);
+        assert_eq!(error("{title"), TemplateError::Unterminated(0));
+    }
+
+    #[test]
+    fn pads_the_integer_part_only() {
+        assert_eq!(pad_number("2.5", 2), "02.5");
+        assert_eq!(pad_number("2", 2), "02");
+        assert_eq!(pad_number("123", 2), "123");
+        assert_eq!(pad_number("II", 2), "II");
+    }
+}
@@ -0,0 +1,220 @@
+use eyre::Result;
+use indicatif::ProgressBar;
+use notify::{
+    event::{AccessKind, AccessMode, ModifyKind, RenameMode},
+    Event, EventKind, RecursiveMode, Watcher,
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::Stage,
+    journal::{Journal, JOURNAL_DIR},
+    nested_output, wanted_format, Args, Pipeline,
+};
+
+/// How often files which are still being written are checked
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How many times an ebook which can't be read is retried before it is reported, as a file
+/// which looked complete may still be being written
+const READ_ATTEMPTS: u32 = 3;
+
+/// A file which was created or changed and has not been sorted yet
+struct Pending {
+    size: Option<u64>,
+    /// When the file last changed
+    changed: Instant,
+    /// Whether the writer closed the file, which means it is complete
+    closed: bool,
+    attempts: u32,
+}
+
+impl Pending {
+    fn new() -> Pending {
+        Pending {
+            size: None,
+            changed: Instant::now(),
+            closed: false,
+            attempts: 0,
+        }
+    }
+}
+
+/// Watch root for new ebooks and sort each one once it has been completely written
+pub fn watch(args: &Args, root: PathBuf, output: PathBuf, settle: Duration) -> Result<()> {
+    let mut pipeline = Pipeline::open(args, output.clone())?;
+    let ignored = [
+        nested_output(&root, &output),
+        Some(output.join(JOURNAL_DIR)),
+    ];
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(&root, RecursiveMode::Recursive)?;
+    println!("Watching {} for new ebooks", root.display());
+
+    let mut pending: HashMap<PathBuf, Pending> = HashMap::new();
+    let mut journal = None;
+
+    loop {
+        match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(event)) => {
+                let is_ignored = |path: &Path| {
+                    ignored.iter().flatten().any(|dir| path.starts_with(dir))
+                        || wanted_format(args, path).is_none()
+                };
+                for (path, change) in changes(event) {
+                    if is_ignored(&path) {
+                        continue;
+                    }
+                    match change {
+                        Change::Written => {
+                            let file = pending.entry(path).or_insert_with(Pending::new);
+                            file.changed = Instant::now();
+                        }
+                        Change::Closed => {
+                            pending.entry(path).or_insert_with(Pending::new).closed = true;
+                        }
+                        Change::Removed => {
+                            pending.remove(&path);
+                        }
+                    }
+                }
+            }
+            Ok(Err(e)) => eprintln!("Watch error: {e}"),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        let ready = take_ready(&mut pending, settle);
+        if ready.is_empty() {
+            continue;
+        }
+
+        let mut work = ready
+            .keys()
+            .filter_map(|path| Some((wanted_format(args, path)?, path.clone())))
+            .collect::<Vec<_>>();
+        work.sort_by(|(_, a), (_, b)| a.cmp(b));
+        let plan = match pipeline.plan(work, &ProgressBar::hidden()) {
+            Ok(plan) => plan,
+            Err(e) => {
+                eprintln!("Failed to plan {} new ebooks: {e}", ready.len());
+                continue;
+            }
+        };
+
+        for error in plan.errors {
+            let path = error.path().to_path_buf();
+            let attempts = ready.get(&path).copied().unwrap_or_default() + 1;
+            if matches!(error.stage(), Stage::Scan | Stage::Parse) && attempts < READ_ATTEMPTS {
+                let mut file = Pending::new();
+                file.attempts = attempts;
+                pending.insert(path, file);
+            } else {
+                eprintln!(
+                    "Failed to {} {}: {}",
+                    error.stage(),
+                    path.display(),
+                    error.message()
+                );
+            }
+        }
+
+        for group in &plan.duplicates {
+            println!(
+                "Duplicate, {}: {}",
+                group.kind,
+                group.members_cell().replace('\n', ", ")
+            );
+        }
+
+        for placement in plan.placements.iter().filter(|p| !p.is_in_place()) {
+            let (source, destination) =
+                (placement.source.display(), placement.destination.display());
+            if args.dry_run {
+                println!("Would place {source} at {destination}");
+                continue;
+            }
+
+            let journal = match journal {
+                Some(ref mut journal) => journal,
+                None => journal.insert(Journal::create(&output)?),
+            };
+            let metadata = plan
+                .scans
+                .get(&placement.source)
+                .and_then(|scan| scan.metadata.as_ref());
+            match pipeline.place(placement, metadata, journal) {
+                Ok(outcome) => println!("{outcome}: {source} -> {destination}"),
+                Err(e) => eprintln!("Failed to {} {source}: {}", e.stage(), e.message()),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// What happened to a file, as far as sorting it is concerned
+enum Change {
+    /// The file was created or written to
+    Written,
+    /// The file was closed after being written to
+    Closed,
+    /// The file is gone, or was renamed away
+    Removed,
+}
+
+fn changes(event: Event) -> Vec<(PathBuf, Change)> {
+    let mut paths = event.paths;
+    match event.kind {
+        EventKind::Create(_) | EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any) => {
+            paths.into_iter().map(|p| (p, Change::Written)).collect()
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+            paths.into_iter().map(|p| (p, Change::Closed)).collect()
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if paths.len() == 2 => {
+            let to = paths.pop().expect("a rename has two paths");
+            let from = paths.pop().expect("a rename has two paths");
+            vec![(from, Change::Removed), (to, Change::Closed)]
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) | EventKind::Remove(_) => {
+            paths.into_iter().map(|p| (p, Change::Removed)).collect()
+        }
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+            paths.into_iter().map(|p| (p, Change::Closed)).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Take the files which are completely written: either closed by their writer, or with a size
+/// which has not changed for `settle`. Returns each file with the number of times it has already
+/// failed to be read.
+fn take_ready(pending: &mut HashMap<PathBuf, Pending>, settle: Duration) -> HashMap<PathBuf, u32> {
+    let mut ready = HashMap::new();
+
+    pending.retain(|path, file| {
+        let Ok(size) = path.metadata().map(|m| m.len()) else {
+            // The file is gone, or not readable yet
+            return file.changed.elapsed() < settle * 10;
+        };
+
+        if file.size != Some(size) {
+            file.size = Some(size);
+            file.changed = Instant::now();
+        }
+        if (file.closed || file.changed.elapsed() >= settle) && size > 0 {
+            ready.insert(path.clone(), file.attempts);
+            return false;
+        }
+        true
+    });
+
+    ready
+}